@@ -1,15 +1,31 @@
 use std::fs;
+use std::io::{self, Write as _};
+use std::ops::ControlFlow;
+use std::path::{Path as StdPath, PathBuf};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::{finish, new_file, okay_or_500, Output};
-use axum::body::{self, BoxBody};
-use axum::extract::{Path, State};
+use crate::config::Config;
+use crate::log::Logger;
+use crate::name;
+use crate::read::{self, for_each_item};
+use crate::{
+    bad_request, compress_options, finish, internal_error, json_error, make_durable, new_file,
+    okay_or_500, unavailable, Durability, Output, Shard, Writer,
+};
+use anyhow::Result;
+use archiv::{Compress, CompressOptions};
+use axum::async_trait;
+use axum::body::{self, Body, BoxBody, HttpBody as _};
+use axum::extract::{FromRequestParts, Path, Query, RawBody, State};
+use axum::http::header::CONTENT_DISPOSITION;
+use axum::http::request::Parts;
 use axum::http::StatusCode;
-use axum::response::Response;
+use axum::response::{IntoResponse as _, Response};
 use axum::Json;
-use bunyarrs::vars_dbg;
-use serde::Serialize;
+use bunyarrs::{vars, vars_dbg};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use serde_json::Value;
 use time::format_description::well_known::Rfc3339;
@@ -20,78 +36,185 @@ use tower_http::services::ServeFile;
 #[derive(Serialize)]
 struct FileListing {
     name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sequence: Option<u64>,
     compressed_size_estimate: u64,
     live: bool,
+    // only with `?stats=true`, and never for the live file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    item_count: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uncompressed_size: Option<u64>,
 }
 
-fn parse_date(date: &str) -> Option<OffsetDateTime> {
-    OffsetDateTime::parse(date, &Rfc3339).ok()
+#[derive(Deserialize)]
+pub struct ListQuery {
+    stats: Option<String>,
 }
 
-pub async fn list_files(State(state): State<Arc<Output>>) -> (StatusCode, Json<Value>) {
-    let logger = &state.logger;
-    let live_name = state
-        .out
-        .lock()
-        .await
-        .as_ref()
-        .map(|v| v.name.to_string())
-        .unwrap_or(String::new());
-    let mut items = Vec::new();
-    okay_or_500(logger, || async {
-        for f in fs::read_dir(".")? {
-            let f = f?;
+/// The `:name` segment of a route, which must (once percent-decoded) be UTF-8, else it's a
+/// 400 like any other invalid name, not axum's plain-text rejection.
+pub struct NameParam(pub String);
 
-            let val = match f.file_name().to_str() {
-                Some(val) => val.to_string(),
-                None => continue,
-            };
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for NameParam {
+    type Rejection = (StatusCode, Json<Value>);
 
-            let ext = ".events.archiv";
-            if !val.ends_with(ext) {
-                continue;
-            }
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match Path::<String>::from_request_parts(parts, state).await {
+            Ok(Path(name)) => Ok(NameParam(name)),
+            Err(_) => Err(bad_request("invalid name")),
+        }
+    }
+}
+
+/// For query parameters; file names have their own parser, in `name`.
+pub fn parse_date(date: &str) -> Option<OffsetDateTime> {
+    OffsetDateTime::parse(date, &Rfc3339).ok()
+}
+
+pub struct EventFile {
+    /// the date part, as used in the api
+    pub name: String,
+    /// the name on disk
+    pub file_name: String,
+    /// where it is, in the data dir
+    pub path: PathBuf,
+    pub start: OffsetDateTime,
+    pub sequence: Option<u64>,
+    disambiguator: Option<u32>,
+}
+
+/// All the event files we can see in `dir`, oldest first.
+pub fn event_files(dir: &StdPath, logger: &Logger) -> Result<Vec<EventFile>> {
+    let mut files = Vec::new();
+    for f in fs::read_dir(dir)? {
+        let f = f?;
 
-            let live = val == live_name;
+        let val = match f.file_name().to_str() {
+            Some(val) => val.to_string(),
+            None => continue,
+        };
 
-            let val = &val[..val.len() - ext.len()];
-            if parse_date(val).is_none() {
+        let name = match name::split_suffix(&val) {
+            Some(name) => name,
+            None => continue,
+        };
+        let parsed = match name::parse_name(name) {
+            Some(parsed) => parsed,
+            None => {
+                logger.warn(vars!(val), "ignoring malformed event file name");
                 continue;
             }
+        };
 
-            let compressed_size_estimate = f.metadata()?.len();
+        files.push(EventFile {
+            name: name.to_string(),
+            file_name: val.to_string(),
+            path: f.path(),
+            start: parsed.instant,
+            sequence: parsed.sequence,
+            disambiguator: parsed.disambiguator,
+        });
+    }
+    // sequenced files are in sequence, after any from before the sequence was turned on
+    files.sort_by_key(|v| (v.sequence.is_some(), v.sequence, v.start, v.disambiguator));
+    Ok(files)
+}
 
-            items.push(FileListing {
-                name: val.to_string(),
-                compressed_size_estimate,
-                live,
-            });
-        }
-        items.sort_by_key(|v| v.name.to_string());
+/// The files which could contain events in the (inclusive) range; each file runs from the
+/// date in its name until the date in the next file's name. With several `shards`, the next
+/// file could be another shard's, so we can't tell when any file ends.
+pub fn files_overlapping(
+    dir: &StdPath,
+    logger: &Logger,
+    shards: usize,
+    from: Option<OffsetDateTime>,
+    to: Option<OffsetDateTime>,
+) -> Result<Vec<EventFile>> {
+    let files = event_files(dir, logger)?;
+    let ends = match shards {
+        1 => files
+            .iter()
+            .skip(1)
+            .map(|f| Some(f.start))
+            .chain([None])
+            .collect::<Vec<_>>(),
+        _ => vec![None; files.len()],
+    };
+    Ok(files
+        .into_iter()
+        .zip(ends)
+        .filter(|(f, end)| {
+            to.is_none_or(|to| f.start <= to)
+                && from.is_none_or(|from| end.is_none_or(|end| end >= from))
+        })
+        .map(|(f, _)| f)
+        .collect())
+}
 
+/// The files in the data dir. With `?stats=true`, each finished file is also decoded to count
+/// its events and their bytes, which is slow, so isn't done by default; the live file's
+/// counts would be out of date by the time anyone read them, so it doesn't get them.
+pub async fn list_files(
+    State(state): State<Arc<Output>>,
+    Query(query): Query<ListQuery>,
+) -> (StatusCode, Json<Value>) {
+    let stats = matches!(query.stats.as_deref(), Some("1" | "true"));
+    okay_or_500(&state.logger, || async {
+        // a big data dir is a lot of `stat`s, and maybe decoding, so keep it off the runtime
+        let state = Arc::clone(&state);
+        let items = tokio::task::spawn_blocking(move || listing(&state, stats)).await??;
         Ok(json! { items })
     })
     .await
 }
 
-pub async fn fetch_raw(State(state): State<Arc<Output>>, Path(name): Path<String>) -> Response {
-    if parse_date(&name).is_none() {
-        return empty_status_response(StatusCode::BAD_REQUEST);
+fn listing(state: &Output, stats: bool) -> Result<Vec<FileListing>> {
+    let mut items = Vec::new();
+    for f in event_files(&state.config.data_dir, &state.logger)? {
+        let live = state.is_live(&f.path);
+        let compressed_size_estimate = fs::metadata(&f.path)?.len();
+        let (item_count, uncompressed_size) = match stats && !live {
+            true => {
+                let (count, size) = read::item_stats(&f.path)?;
+                (Some(count), Some(size))
+            }
+            false => (None, None),
+        };
+
+        items.push(FileListing {
+            name: f.name,
+            sequence: f.sequence,
+            compressed_size_estimate,
+            live,
+            item_count,
+            uncompressed_size,
+        });
     }
+    Ok(items)
+}
 
-    let file_name = format!("{}.events.archiv", name);
-    match ServeFile::new_with_mime(
-        file_name,
-        &"application/zstd".parse().expect("static mime type"),
-    )
-    .oneshot(axum::http::Request::new(body::Body::empty()))
-    .await
+pub async fn fetch_raw(State(state): State<Arc<Output>>, NameParam(name): NameParam) -> Response {
+    let Some(path) = name::path(&state.config.data_dir, &name) else {
+        return empty_status_response(StatusCode::BAD_REQUEST);
+    };
+
+    match ServeFile::new_with_mime(path, &"application/zstd".parse().expect("static mime type"))
+        .oneshot(axum::http::Request::new(body::Body::empty()))
+        .await
     {
-        //     extra: Header::new(
-        //         "Content-Disposition",
-        //         format!("attachment; filename=\"{}\"", file_name),
-        //     ),
-        Ok(res) => res.map(body::boxed),
+        Ok(mut res) => {
+            if res.status().is_success() {
+                // colons upset some operating systems, as with `read::read_file`'s CSV
+                let file_name = format!("{name}{}", name::SUFFIX).replace(':', "-");
+                let disposition = format!("attachment; filename=\"{file_name}\"");
+                if let Ok(disposition) = disposition.parse() {
+                    res.headers_mut().insert(CONTENT_DISPOSITION, disposition);
+                }
+            }
+            res.map(body::boxed)
+        }
         Err(err) => {
             state.logger.warn(vars_dbg!(err), "unable to serve file");
             empty_status_response(StatusCode::INTERNAL_SERVER_ERROR)
@@ -99,6 +222,435 @@ pub async fn fetch_raw(State(state): State<Arc<Output>>, Path(name): Path<String
     }
 }
 
+#[derive(Deserialize)]
+pub struct RecompressQuery {
+    level: i32,
+}
+
+/// Rewrite a finished file at a different zstd level, e.g. a higher one for cold data.
+///
+/// The items (including their timestamp prefixes) are copied verbatim into a temporary file,
+/// which is renamed over the original once it's complete, so readers see one or the other.
+pub async fn recompress(
+    State(state): State<Arc<Output>>,
+    NameParam(name): NameParam,
+    Query(query): Query<RecompressQuery>,
+) -> (StatusCode, Json<Value>) {
+    let Some(path) = name::path(&state.config.data_dir, &name) else {
+        return bad_request("invalid name");
+    };
+    if !(1..=22).contains(&query.level) {
+        return bad_request("level must be between 1 and 22");
+    }
+
+    if state.is_live(&path) {
+        return json_error(StatusCode::CONFLICT, "refusing to recompress the live file");
+    }
+    if !fs::metadata(&path).is_ok_and(|m| m.is_file()) {
+        return json_error(StatusCode::NOT_FOUND, "no such file");
+    }
+
+    let level = query.level;
+    let recompressed = tokio::task::spawn_blocking(move || recompress_file(&path, level)).await;
+    let (before, after) = match recompressed.map_err(anyhow::Error::from).and_then(|v| v) {
+        Ok(Some(sizes)) => sizes,
+        Ok(None) => return json_error(StatusCode::CONFLICT, "file already being recompressed"),
+        Err(err) => return internal_error(&state.logger, err),
+    };
+    // the catalog still has its old size
+    state.catalog_stale.notify_one();
+    state
+        .logger
+        .info(vars!(name, level, before, after), "recompressed file");
+    let body = json!({ "level": level, "before_bytes": before, "after_bytes": after });
+    (StatusCode::OK, Json(body))
+}
+
+/// Find files a previous run didn't get to finish (e.g. it was killed), and finish them now, so
+/// file boundaries line up with process lifetimes. Half-written temporary files from
+/// recompression or import are worthless, and removed, as are zero-byte event files. Files
+/// we've `resumed` are live again, so are left alone.
+pub fn finish_leftovers(dir: &StdPath, logger: &Logger, resumed: &[PathBuf]) -> Result<()> {
+    for f in fs::read_dir(dir)? {
+        let f = f?;
+        let val = match f.file_name().to_str() {
+            Some(val) => val.to_string(),
+            None => continue,
+        };
+        if [".recompress.tmp", ".import.tmp", ".resume.tmp"]
+            .iter()
+            .any(|suffix| val.ends_with(suffix))
+        {
+            logger.warn(vars!(val), "removing leftover temporary file");
+            fs::remove_file(f.path())?;
+        }
+    }
+
+    for f in event_files(dir, logger)? {
+        if resumed.contains(&f.path) {
+            continue;
+        }
+        let file_name = f.file_name;
+        // we died before writing anything at all; there's nothing worth keeping
+        if fs::metadata(&f.path)?.len() == 0 {
+            logger.warn(vars!(file_name), "startup rotation: removing empty file");
+            fs::remove_file(&f.path)?;
+            continue;
+        }
+        match read::is_finished(&f.path) {
+            Ok(true) => continue,
+            Ok(false) => (),
+            Err(err) => {
+                logger.warn(vars_dbg!(file_name, err), "unable to check leftover file");
+                continue;
+            }
+        }
+        // rewriting it is the only way to get a footer on the end; the temporary files were all
+        // removed above, so nobody else can be doing it
+        let Some((before, after)) = recompress_file(&f.path, 0)? else {
+            continue;
+        };
+        logger.info(
+            vars!(file_name, before, after),
+            "startup rotation: finished file left by a previous run",
+        );
+    }
+    Ok(())
+}
+
+/// If a previous run didn't finish the newest files (one per shard), and their names say they
+/// were started `within` the last little while, carry on writing to them, so a quick restart
+/// doesn't leave a fragment of a file behind. Anything unsuitable is left for
+/// `finish_leftovers`, and shards which don't get a file start new ones, as normal.
+///
+/// archiv has no way to reopen a `CompressStream`, and (like zstd underneath it) can't be
+/// appended to: an unfinished file stops part way through a compressed frame, and maybe part
+/// way through an item, and anything written after that would be unreadable. So, rather than
+/// reopening it, the complete items are copied, as they are (header and markers included),
+/// into a new stream, which is synced and renamed over the original, then kept open as the
+/// live file. A truncated frame at the end is dropped, along with any items in it; they were
+/// never readable, so readers, who see the old file or the new one and never a mix, lose
+/// nothing they could have had.
+///
+/// Files with no events are left alone, as are files whose framing (see `read::TYPED_TS` and
+/// `read::PRECISION_TS`) isn't what we'd write now, as the new events would have to match it.
+/// Resumed files aren't mirrored, as the mirror's copy would be just as unfinished.
+pub fn resume_leftovers(config: &Config, logger: &Logger, within: Duration) -> Result<Vec<Writer>> {
+    let now = OffsetDateTime::now_utc();
+    let mut writers = Vec::new();
+    // only the newest, or events would be going into a file older than a finished one
+    for f in event_files(&config.data_dir, logger)?
+        .into_iter()
+        .rev()
+        .take(config.shards)
+    {
+        if now - f.start > within {
+            continue;
+        }
+        let file_name = f.file_name.clone();
+        match resume_file(config, f) {
+            Ok(Some(writer)) => {
+                let items = writer.items;
+                logger.info(
+                    vars!(file_name, items),
+                    "startup: resumed file left by a previous run",
+                );
+                writers.push(writer);
+            }
+            Ok(None) => (),
+            Err(err) => logger.warn(vars_dbg!(file_name, err), "unable to resume file"),
+        }
+    }
+    Ok(writers)
+}
+
+/// Copy the complete items of an unfinished file into a new stream in its place, as
+/// `resume_leftovers` says, or None if it's not suitable.
+fn resume_file(config: &Config, f: EventFile) -> Result<Option<Writer>> {
+    // decided before anything is copied, so unsuitable files cost a read, not a rewrite
+    if !framing_matches(config, &f.path)? || read::is_finished(&f.path)? {
+        return Ok(None);
+    }
+    let temp = temp_path(&f.path, ".resume.tmp");
+    let writer = match copy_for_resume(config, &f, &temp) {
+        Ok(writer) => writer,
+        Err(err) => {
+            let _ = fs::remove_file(&temp);
+            return Err(err);
+        }
+    };
+    fs::rename(&temp, &f.path)?;
+    fs::File::open(&config.data_dir)?.sync_all()?;
+    Ok(Some(writer))
+}
+
+/// Whether the file has any events, and its markers (which all come before them) say they're
+/// framed as we'd write new ones.
+fn framing_matches(config: &Config, path: &StdPath) -> Result<bool> {
+    let mut typed = false;
+    let mut precision = Some(read::Precision::Seconds);
+    let mut events = false;
+    read::walk(path, |ts, body| {
+        match ts {
+            read::HEADER_TS => (),
+            read::TYPED_TS => typed = true,
+            read::PRECISION_TS => {
+                precision = std::str::from_utf8(body).ok().and_then(|v| v.parse().ok());
+            }
+            _ => {
+                events = true;
+                return ControlFlow::Break(());
+            }
+        }
+        ControlFlow::Continue(())
+    })?;
+    Ok(
+        events
+            && typed == config.keep_content_type
+            && precision == Some(config.timestamp_precision),
+    )
+}
+
+fn copy_for_resume(config: &Config, f: &EventFile, temp: &StdPath) -> Result<Writer> {
+    let mut out = compress_options(config).stream_compress(fs::File::create(temp)?)?;
+    let mut items = 0u64;
+    let mut failure = None;
+    read::for_each_raw_item(&f.path, |ts, body| {
+        if !matches!(ts, read::HEADER_TS | read::TYPED_TS | read::PRECISION_TS) {
+            items += 1;
+        }
+        match out.write_item_vectored(&[&ts.to_le_bytes(), body]) {
+            Ok(_) => ControlFlow::Continue(()),
+            Err(err) => {
+                failure = Some(err);
+                ControlFlow::Break(())
+            }
+        }
+    })?;
+    if let Some(err) = failure {
+        return Err(err.into());
+    }
+    make_durable(&mut out, Durability::Synced)?;
+
+    Ok(Writer {
+        inner: out,
+        name: f.file_name.clone(),
+        path: f.path.clone(),
+        created: f.start,
+        items,
+        mirror: None,
+        suspect: false,
+        preallocated: false,
+        typed: config.keep_content_type,
+        precision: config.timestamp_precision,
+    })
+}
+
+/// Rewrite the file at `level`, returning its sizes before and after, or None if someone else
+/// is already recompressing it.
+fn recompress_file(path: &StdPath, level: i32) -> Result<Option<(u64, u64)>> {
+    let original = fs::metadata(path)?;
+    let temp = temp_path(path, ".recompress.tmp");
+    // as in `import`, if the temp file is already there, it's someone else's
+    let opened = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp);
+    let out = match opened {
+        Ok(out) => out,
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    if let Err(err) = write_recompressed(path, out, level, &original) {
+        let _ = fs::remove_file(&temp);
+        return Err(err);
+    }
+    fs::rename(&temp, path)?;
+    Ok(Some((original.len(), fs::metadata(path)?.len())))
+}
+
+/// Next to `path`, with the `suffix` added, for things to be renamed into place.
+fn temp_path(path: &StdPath, suffix: &str) -> PathBuf {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(suffix);
+    PathBuf::from(temp)
+}
+
+fn write_recompressed(
+    path: &StdPath,
+    temp: fs::File,
+    level: i32,
+    original: &fs::Metadata,
+) -> Result<()> {
+    let opts = CompressOptions::default().with_level(level);
+    let mut out = opts.stream_compress(temp)?;
+    let mut failure = None;
+    read::for_each_raw_item(path, |ts, body| {
+        match out.write_item_vectored(&[&ts.to_le_bytes(), body]) {
+            Ok(_) => ControlFlow::Continue(()),
+            Err(err) => {
+                failure = Some(err);
+                ControlFlow::Break(())
+            }
+        }
+    })?;
+    if let Some(err) = failure {
+        return Err(err.into());
+    }
+    let file = out.finish()?;
+    if let Ok(modified) = original.modified() {
+        file.set_modified(modified)?;
+    }
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Restore a file (e.g. from a backup) into the data directory, so the read endpoints serve it.
+///
+/// The body is spooled to a temporary file and checked to be a readable archiv before it's
+/// linked into place; an existing file of the same name is never overwritten. Like `/store`,
+/// this is refused if it would leave less than `BATCHY_MIN_FREE_BYTES` free.
+pub async fn import(
+    State(state): State<Arc<Output>>,
+    NameParam(name): NameParam,
+    RawBody(mut body): RawBody,
+) -> Response {
+    let Some(path) = name::path(&state.config.data_dir, &name) else {
+        return bad_request("invalid name").into_response();
+    };
+    if fs::symlink_metadata(&path).is_ok() {
+        return json_error(StatusCode::CONFLICT, "file already exists").into_response();
+    }
+    if state.free_bytes.load(Ordering::Relaxed) < state.config.min_free_bytes {
+        return no_space(&state);
+    }
+
+    let temp_name = temp_path(&path, ".import.tmp");
+    // if someone else is importing the same name, the temp file is theirs
+    let opened = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp_name);
+    let mut temp = match opened {
+        Ok(temp) => temp,
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            return json_error(StatusCode::CONFLICT, "file already being imported").into_response();
+        }
+        Err(err) => return internal_error(&state.logger, err.into()).into_response(),
+    };
+    if let Err(resp) = spool(&state, &mut temp, &mut body).await {
+        let _ = fs::remove_file(&temp_name);
+        return resp;
+    }
+
+    let mut items = 0u64;
+    let valid = tokio::task::block_in_place(|| {
+        fs::metadata(&temp_name).is_ok_and(|m| m.len() > 0)
+            && for_each_item(&temp_name, |_, _| {
+                items += 1;
+                ControlFlow::Continue(())
+            })
+            .is_ok()
+    });
+    if !valid {
+        let _ = fs::remove_file(&temp_name);
+        return bad_request("not a readable archiv").into_response();
+    }
+
+    okay_or_500(&state.logger, || async {
+        // unlike rename, this refuses to replace anything that appeared in the meantime
+        let linked = fs::hard_link(&temp_name, &path);
+        fs::remove_file(&temp_name)?;
+        linked?;
+        state.logger.info(vars!(name, items), "imported file");
+        Ok(json!({ "name": name, "items": items }))
+    })
+    .await
+    .into_response()
+}
+
+// bigger than any file we'd write without `BATCHY_MAX_FILE_BYTES`, but not unbounded
+const MAX_IMPORT_BYTES: u64 = 16 * 1024 * 1024 * 1024;
+
+/// Copy the body into `temp`, and get it onto the disk, so long as it's under
+/// `MAX_IMPORT_BYTES` and fits in the space `BATCHY_MIN_FREE_BYTES` leaves us.
+async fn spool(state: &Output, temp: &mut fs::File, body: &mut Body) -> Result<(), Response> {
+    // as of the last check, so this can't account for everyone else writing meanwhile
+    let room = state
+        .free_bytes
+        .load(Ordering::Relaxed)
+        .saturating_sub(state.config.min_free_bytes);
+    let mut received = 0u64;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| {
+            state
+                .logger
+                .warn(vars_dbg!(err), "unable to receive import");
+            bad_request("unable to receive body").into_response()
+        })?;
+        received += chunk.len() as u64;
+        if received > MAX_IMPORT_BYTES {
+            let body = json!({ "error": "too long", "limit_bytes": MAX_IMPORT_BYTES });
+            return Err((StatusCode::PAYLOAD_TOO_LARGE, Json(body)).into_response());
+        }
+        if received > room {
+            return Err(no_space(state));
+        }
+        tokio::task::block_in_place(|| temp.write_all(&chunk))
+            .map_err(|err| internal_error(&state.logger, err.into()).into_response())?;
+    }
+    tokio::task::block_in_place(|| temp.sync_all())
+        .map_err(|err| internal_error(&state.logger, err.into()).into_response())
+}
+
+fn no_space(state: &Output) -> Response {
+    unavailable(
+        state.config.retry_after,
+        json!({ "error": "insufficient disk space" }),
+    )
+}
+
+/// Remove a finished file, e.g. to honour an erasure request; there's no undo.
+pub async fn delete_file(
+    State(state): State<Arc<Output>>,
+    NameParam(name): NameParam,
+) -> (StatusCode, Json<Value>) {
+    let Some(path) = name::path(&state.config.data_dir, &name) else {
+        return bad_request("invalid name");
+    };
+    // a file can only stop being live, so this can't change once we've let go
+    let mut live = false;
+    for shard in &state.shards {
+        live |= state
+            .lock_writer(shard)
+            .await
+            .as_ref()
+            .is_some_and(|writer| writer.path == path);
+    }
+    if live {
+        return json_error(StatusCode::CONFLICT, "refusing to delete the live file");
+    }
+
+    match fs::remove_file(&path) {
+        Ok(()) => (),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return json_error(StatusCode::NOT_FOUND, "no such file");
+        }
+        Err(err) => return internal_error(&state.logger, err.into()),
+    }
+    state
+        .ratio_cache
+        .lock()
+        .expect("not poisoned")
+        .remove(&path);
+    // we don't know which of the buffered events were in the file, so `/api/tail` goes back to
+    // reading what's left on disk until the buffer fills up again
+    state.recent.lock().expect("not poisoned").clear();
+    state.catalog_stale.notify_one();
+    state.logger.info(vars!(name), "deleted file");
+    (StatusCode::OK, Json(json!({ "deleted": name })))
+}
+
 fn empty_status_response(status_code: StatusCode) -> Response {
     Response::builder()
         .status(status_code)
@@ -108,38 +660,247 @@ fn empty_status_response(status_code: StatusCode) -> Response {
 
 pub async fn cycle(State(state): State<Arc<Output>>) -> (StatusCode, Json<Value>) {
     okay_or_500(&state.logger, || async {
-        let mut previous = state.out.lock().await.replace(new_file(&state.logger)?);
+        for shard in &state.shards {
+            // as in `append_inner`, creating the file is blocking, and we're holding the lock
+            let mut previous = {
+                let mut opt = state.lock_writer(shard).await;
+                let next = tokio::task::block_in_place(|| new_file(&state.logger, &state.config))?;
+                opt.replace(next)
+            };
 
-        finish(&state.logger, &mut previous)?;
+            // flushing out the rest of the old file doesn't need the lock, but is just as slow
+            tokio::task::block_in_place(|| finish(&state.logger, &mut previous))?;
+            // letting go of the lock said the file had changed, but it wasn't finished then
+            state.catalog_stale.notify_one();
+        }
         Ok(json!({}))
     })
     .await
 }
 
-pub async fn time_based_cycle(output: Arc<Output>) {
-    let hour = 60 * 60;
-    let mut interval = tokio::time::interval(Duration::from_secs(24 * hour));
+/// Finish the live file and get it onto the disk, then carry on in a new one; run this before
+/// taking a backup or snapshot.
+///
+/// Once this returns, the named file is complete, it and its directory entry have been fsynced,
+/// and nothing more will be written to it. Events stored while this is running end up in either
+/// it or the new file. `/api/cycle` does the same, but without waiting for the disk.
+///
+/// With `BATCHY_SHARDS`, every shard's file is checkpointed, and they're listed as `names`.
+pub async fn checkpoint(State(state): State<Arc<Output>>) -> (StatusCode, Json<Value>) {
+    okay_or_500(&state.logger, || async {
+        let mut names = Vec::new();
+        for shard in &state.shards {
+            names.push(checkpoint_shard(&state, shard).await?);
+        }
+        tokio::task::block_in_place(|| fs::File::open(&state.config.data_dir)?.sync_all())?;
+        match names.as_slice() {
+            [name] => Ok(json!({ "name": name, "durable": true })),
+            names => Ok(json!({ "names": names, "durable": true })),
+        }
+    })
+    .await
+}
+
+/// Replace the shard's file, and get the old one, if there was one, onto the disk, except for
+/// its directory entry. Returns the old one's name.
+async fn checkpoint_shard(state: &Output, shard: &Shard) -> Result<Option<String>> {
+    let previous = {
+        let mut opt = state.lock_writer(shard).await;
+        let writer = tokio::task::block_in_place(|| new_file(&state.logger, &state.config))?;
+        opt.replace(writer)
+    };
+    let Some(mut writer) = previous else {
+        return Ok(None);
+    };
+
+    // the mirror is best-effort, and isn't fsynced
+    let file_name = writer.name.clone();
+    tokio::task::block_in_place(|| -> Result<()> {
+        writer.finish_mirror(&state.logger);
+        writer.finish()?.sync_all()?;
+        Ok(())
+    })?;
+    // as in `cycle`, the catalog was told before the file was finished
+    state.catalog_stale.notify_one();
+    state.logger.info(vars!(file_name), "checkpointed file");
+    let name = name::split_suffix(&file_name).unwrap_or(&file_name);
+    Ok(Some(name.to_string()))
+}
+
+/// Stop advertising readiness, so the load balancer stops sending us new traffic,
+/// but keep accepting whatever is still in flight. After the grace period, it's
+/// safe to SIGTERM us, which finishes the live file as normal.
+pub async fn drain(State(state): State<Arc<Output>>) -> (StatusCode, Json<Value>) {
+    if !state.draining.swap(true, Ordering::Relaxed) {
+        state.logger.info((), "draining, readiness withdrawn");
+    }
+    (StatusCode::OK, Json(json!({"draining": true})))
+}
+
+/// Refuse new events (with a 503) without touching the writer, e.g. while taking a snapshot.
+/// Rotation and shutdown carry on as normal.
+pub async fn pause(State(state): State<Arc<Output>>) -> (StatusCode, Json<Value>) {
+    if !state.paused.swap(true, Ordering::Relaxed) {
+        state.logger.info((), "ingestion paused");
+    }
+    (StatusCode::OK, Json(json!({"paused": true})))
+}
+
+pub async fn resume(State(state): State<Arc<Output>>) -> (StatusCode, Json<Value>) {
+    if state.paused.swap(false, Ordering::Relaxed) {
+        state.logger.info((), "ingestion resumed");
+    }
+    (StatusCode::OK, Json(json!({"paused": false})))
+}
+
+/// Whether `/store` is taking events, and everything that could stop it: `/api/pause`, low
+/// disk, and the circuit breaker (see `breaker::Breaker`). `/api/drain` only withdraws
+/// readiness, but is here too.
+pub async fn status(State(state): State<Arc<Output>>) -> (StatusCode, Json<Value>) {
+    let paused = state.paused.load(Ordering::Relaxed);
+    let free_bytes = state.free_bytes.load(Ordering::Relaxed);
+    let min_free_bytes = state.config.min_free_bytes;
+    // not `allow`, which would use up a half-open breaker's trial write
+    let breaker = state.breaker.state(Instant::now());
+    let accepting = !paused && free_bytes >= min_free_bytes && breaker != "open";
+    let status = json!({
+        "accepting": accepting,
+        "paused": paused,
+        "draining": state.draining.load(Ordering::Relaxed),
+        "free_bytes": free_bytes,
+        "min_free_bytes": min_free_bytes,
+        "breaker": breaker,
+    });
+    (StatusCode::OK, Json(status))
+}
+
+/// What would make the live file rotate, and how close it is.
+pub async fn rotation(State(state): State<Arc<Output>>) -> (StatusCode, Json<Value>) {
+    okay_or_500(&state.logger, || async {
+        let next = state.next_time_cycle.load(Ordering::Relaxed);
+        let next = match next {
+            0 => None,
+            next => Some(OffsetDateTime::from_unix_timestamp(next)?.format(&Rfc3339)?),
+        };
+        let triggers = json!({
+            "time": {
+                "active": true,
+                "interval_secs": state.config.cycle_interval.as_secs(),
+                "next": next,
+            },
+            "size": {
+                "active": state.config.max_file_bytes > 0,
+                "max_bytes": state.config.max_file_bytes,
+            },
+            "events": {
+                "active": state.config.max_events > 0,
+                "max_events": state.config.max_events,
+            },
+        });
+
+        // each shard rotates separately, so with more than one, this is a list
+        let mut progress = Vec::new();
+        for shard in &state.shards {
+            let opt = state.lock_writer(shard).await;
+            progress.push(match opt.as_ref() {
+                Some(writer) => json!({
+                    "file_name": writer.name,
+                    "bytes": fs::metadata(&writer.path)?.len(),
+                    "items": writer.items,
+                    "age_secs": (OffsetDateTime::now_utc() - writer.created).whole_seconds(),
+                }),
+                None => Value::Null,
+            });
+        }
+        let progress = match progress.len() {
+            1 => progress.remove(0),
+            _ => Value::from(progress),
+        };
+        Ok(json!({ "triggers": triggers, "progress": progress }))
+    })
+    .await
+}
+
+pub async fn time_based_cycle(output: Arc<Output>, every: Duration) {
+    let interval_secs = every.as_secs();
+    output
+        .scheduler_logger
+        .info(vars!(interval_secs), "time-based rotation started");
+    let mut interval = tokio::time::interval(every);
     // consume initial "immediate" firing
     interval.tick().await;
 
     loop {
+        let next = OffsetDateTime::now_utc() + every;
+        output
+            .next_time_cycle
+            .store(next.unix_timestamp(), Ordering::Relaxed);
         interval.tick().await;
 
-        let mut opt = output.out.lock().await;
-        if let Err(err) = finish(&output.logger, &mut opt) {
-            output
-                .logger
-                .error(vars_dbg!(err), "unable to time-based finish");
+        for shard in &output.shards {
+            let mut opt = output.lock_writer(shard).await;
+            if let Err(err) =
+                tokio::task::block_in_place(|| finish(&output.scheduler_logger, &mut opt))
+            {
+                output
+                    .scheduler_logger
+                    .error(vars_dbg!(err), "unable to time-based finish");
+            }
+            match tokio::task::block_in_place(|| new_file(&output.scheduler_logger, &output.config))
+            {
+                Ok(next) => {
+                    opt.replace(next);
+                }
+                Err(err) => {
+                    output
+                        .scheduler_logger
+                        .error(vars_dbg!(err), "unable to time-based refresh");
+                }
+            };
         }
-        match new_file(&output.logger) {
-            Ok(next) => {
-                opt.replace(next);
+    }
+}
+
+/// If someone deletes the live file out from under us, our writes go to an unlinked inode,
+/// and would be lost when we eventually finish it. Notice, and start a fresh file.
+pub async fn watch_live_file(output: Arc<Output>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(10));
+
+    loop {
+        interval.tick().await;
+
+        for shard in &output.shards {
+            let mut opt = output.lock_writer(shard).await;
+            let (file_name, path) = match opt.as_ref() {
+                Some(writer) => (writer.name.to_string(), writer.path.clone()),
+                None => continue,
+            };
+            match tokio::task::block_in_place(|| fs::metadata(path)) {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                _ => continue,
             }
-            Err(err) => {
+
+            output
+                .scheduler_logger
+                .error(vars!(file_name), "live file deleted externally, replacing");
+            if let Err(err) =
+                tokio::task::block_in_place(|| finish(&output.scheduler_logger, &mut opt))
+            {
                 output
-                    .logger
-                    .error(vars_dbg!(err), "unable to time-based refresh");
+                    .scheduler_logger
+                    .warn(vars_dbg!(err), "unable to finish orphaned file");
             }
-        };
+            match tokio::task::block_in_place(|| new_file(&output.scheduler_logger, &output.config))
+            {
+                Ok(next) => {
+                    opt.replace(next);
+                }
+                Err(err) => {
+                    output
+                        .scheduler_logger
+                        .error(vars_dbg!(err), "unable to replace deleted file");
+                }
+            };
+        }
     }
 }
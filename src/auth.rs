@@ -0,0 +1,37 @@
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::{json_error, Output};
+
+/// Refuse (with a 401) any request without `Authorization: Bearer <BATCHY_TOKEN>`; only
+/// layered on to the routes if there is a token.
+pub async fn require_token<B>(
+    State(state): State<Arc<Output>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let presented = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.as_bytes().strip_prefix(b"Bearer "));
+    let expected = state.config.token.as_deref().map(str::as_bytes);
+    match (presented, expected) {
+        (Some(presented), Some(expected)) if same(presented, expected) => next.run(req).await,
+        _ => (
+            [(WWW_AUTHENTICATE, "Bearer")],
+            json_error(StatusCode::UNAUTHORIZED, "unauthorized"),
+        )
+            .into_response(),
+    }
+}
+
+/// Compare without stopping at the first difference, so how long it takes doesn't say how much
+/// of a guess was right.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,165 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Stop accepting writes for a while if they keep failing, e.g. the disk has gone read-only,
+/// so clients get a quick 503 to back off on, instead of a storm of 500s.
+///
+/// Once `threshold` writes have failed within `window`, the breaker opens, and refuses
+/// everything for `cooldown`. After that, it lets one write through (per `cooldown`) to test
+/// the water: if that works, it closes again, and if it fails, it stays open.
+pub struct Breaker {
+    threshold: usize,
+    window: Duration,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    failures: VecDeque<Instant>,
+    open_until: Option<Instant>,
+}
+
+/// A change of state the caller probably wants to log.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Transition {
+    Opened,
+    Closed,
+}
+
+impl Breaker {
+    /// A `threshold` of zero never opens.
+    pub fn new(threshold: usize, window: Duration, cooldown: Duration) -> Breaker {
+        Breaker {
+            threshold,
+            window,
+            cooldown,
+            inner: Default::default(),
+        }
+    }
+
+    /// Whether a write should be attempted now.
+    pub fn allow(&self, now: Instant) -> bool {
+        let mut inner = self.inner.lock().expect("not poisoned");
+        match inner.open_until {
+            None => true,
+            Some(until) if now < until => false,
+            Some(_) => {
+                // half-open: this one is the trial, and nobody else gets one for a while,
+                // even if it never reports back
+                inner.open_until = Some(now + self.cooldown);
+                true
+            }
+        }
+    }
+
+    pub fn record(&self, now: Instant, ok: bool) -> Option<Transition> {
+        let mut inner = self.inner.lock().expect("not poisoned");
+        if ok {
+            if inner.open_until.take().is_some() {
+                inner.failures.clear();
+                return Some(Transition::Closed);
+            }
+            return None;
+        }
+
+        if self.threshold == 0 {
+            return None;
+        }
+        inner.failures.push_back(now);
+        while inner
+            .failures
+            .front()
+            .is_some_and(|&failed| now.duration_since(failed) > self.window)
+        {
+            inner.failures.pop_front();
+        }
+        // keep the window bounded, however fast things are failing
+        if inner.failures.len() > self.threshold {
+            inner.failures.pop_front();
+        }
+
+        let was_open = inner.open_until.is_some();
+        if was_open || inner.failures.len() >= self.threshold {
+            inner.open_until = Some(now + self.cooldown);
+        }
+        match was_open {
+            false if inner.open_until.is_some() => Some(Transition::Opened),
+            _ => None,
+        }
+    }
+
+    /// `closed`, `open`, or `half-open` (i.e. waiting for a trial write).
+    pub fn state(&self, now: Instant) -> &'static str {
+        match self.inner.lock().expect("not poisoned").open_until {
+            None => "closed",
+            Some(until) if now < until => "open",
+            Some(_) => "half-open",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> Breaker {
+        Breaker::new(3, Duration::from_secs(60), Duration::from_secs(10))
+    }
+
+    #[test]
+    fn trips_and_recovers() {
+        let b = breaker();
+        let start = Instant::now();
+        assert_eq!(None, b.record(start, false));
+        assert_eq!(None, b.record(start, false));
+        assert!(b.allow(start));
+        assert_eq!(Some(Transition::Opened), b.record(start, false));
+        assert!(!b.allow(start));
+        assert_eq!("open", b.state(start));
+
+        let later = start + Duration::from_secs(11);
+        assert_eq!("half-open", b.state(later));
+        assert!(b.allow(later));
+        // only one trial at a time
+        assert!(!b.allow(later));
+        assert_eq!(Some(Transition::Closed), b.record(later, true));
+        assert!(b.allow(later));
+        assert_eq!("closed", b.state(later));
+    }
+
+    #[test]
+    fn failed_trial() {
+        let b = breaker();
+        let start = Instant::now();
+        for _ in 0..3 {
+            b.record(start, false);
+        }
+        let later = start + Duration::from_secs(11);
+        assert!(b.allow(later));
+        assert_eq!(None, b.record(later, false));
+        assert!(!b.allow(later + Duration::from_secs(5)));
+        assert!(b.allow(later + Duration::from_secs(11)));
+    }
+
+    #[test]
+    fn old_failures_forgotten() {
+        let b = breaker();
+        let start = Instant::now();
+        b.record(start, false);
+        b.record(start, false);
+        assert_eq!(None, b.record(start + Duration::from_secs(61), false));
+        assert!(b.allow(start + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn disabled() {
+        let b = Breaker::new(0, Duration::from_secs(60), Duration::from_secs(10));
+        let start = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(None, b.record(start, false));
+        }
+        assert!(b.allow(start));
+    }
+}
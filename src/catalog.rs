@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead as _, Write as _};
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use bunyarrs::vars_dbg;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+use crate::admin::event_files;
+use crate::read;
+use crate::Output;
+
+const CATALOG: &str = "catalog.jsonl";
+const CATALOG_TMP: &str = "catalog.jsonl.tmp";
+
+/// Rewritten this often even if nothing rotated, to pick up files which appeared (imports),
+/// or went away (retention, or an operator).
+const INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// A line of `catalog.jsonl`, describing one finished file.
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+    /// as used in the api
+    name: String,
+    bytes: u64,
+    items: u64,
+    /// the first and last events' timestamps (not the header's), if there are any
+    first: Option<String>,
+    last: Option<String>,
+    /// of the whole file, as it is on disk, in hex
+    sha256: String,
+}
+
+/// Keep `catalog.jsonl` listing every finished file, oldest first, for tools which would rather
+/// watch a file than poll `/api/raw`. It's replaced atomically, on startup, whenever the live
+/// file changes, and every `INTERVAL`.
+///
+/// Only files we haven't seen at this size before are read, which includes all of them on the
+/// first run; after that, the previous catalog is trusted.
+pub async fn maintain(output: Arc<Output>) {
+    let mut known = match load(&output.config.data_dir) {
+        Ok(known) => known,
+        Err(err) => {
+            output
+                .scheduler_logger
+                .warn(vars_dbg!(err), "unable to read old catalog, rebuilding");
+            HashMap::new()
+        }
+    };
+    let mut interval = tokio::time::interval(INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = output.catalog_stale.notified() => {},
+        }
+
+        // not `spawn_blocking`: that would carry on after shutdown cancelled us, and could still
+        // be reading the live file when it's finished; there's no await point in here to stop at
+        match tokio::task::block_in_place(|| rebuild(&output, known.clone())) {
+            Ok(rebuilt) => known = rebuilt,
+            Err(err) => output
+                .scheduler_logger
+                .error(vars_dbg!(err), "unable to write catalog"),
+        }
+    }
+}
+
+fn load(dir: &Path) -> Result<HashMap<String, (u64, Entry)>> {
+    let file = match fs::File::open(dir.join(CATALOG)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut known = HashMap::new();
+    for line in io::BufReader::new(file).lines() {
+        let entry: Entry = serde_json::from_str(&line?)?;
+        known.insert(entry.name.clone(), (entry.bytes, entry));
+    }
+    Ok(known)
+}
+
+fn rebuild(
+    state: &Output,
+    mut known: HashMap<String, (u64, Entry)>,
+) -> Result<HashMap<String, (u64, Entry)>> {
+    let dir = &state.config.data_dir;
+    let mut current = HashMap::new();
+    let mut out = io::BufWriter::new(fs::File::create(dir.join(CATALOG_TMP))?);
+    for file in event_files(dir, &state.scheduler_logger)? {
+        if state.is_live(&file.path) {
+            continue;
+        }
+        let bytes = fs::metadata(&file.path)?.len();
+        let entry = match known.remove(&file.name) {
+            Some((was, entry)) if was == bytes => Some(entry),
+            _ => match describe(&file.name, &file.path, bytes) {
+                Ok(entry) => entry,
+                // e.g. a corrupt import; leaving it out is better than never updating the rest
+                Err(err) => {
+                    let file_name = &file.file_name;
+                    state.scheduler_logger.warn(
+                        vars_dbg!(file_name, err),
+                        "unable to describe file for the catalog",
+                    );
+                    continue;
+                }
+            },
+        };
+        // not finished, e.g. left by a crash; it'll appear if someone finishes it
+        let Some(entry) = entry else {
+            continue;
+        };
+        serde_json::to_writer(&mut out, &entry)?;
+        out.write_all(b"\n")?;
+        current.insert(file.name, (bytes, entry));
+    }
+    let out = out.into_inner().map_err(|err| err.into_error())?;
+    out.sync_all()?;
+    fs::rename(dir.join(CATALOG_TMP), dir.join(CATALOG))?;
+    Ok(current)
+}
+
+/// Read the whole file, or `None` if it isn't finished.
+fn describe(name: &str, path: &Path, bytes: u64) -> Result<Option<Entry>> {
+    let mut items = 0u64;
+    let mut first = None;
+    let mut last = None;
+    let finished = read::walk_events(path, |ts, _, _| {
+        items += 1;
+        first.get_or_insert(ts.secs());
+        last = Some(ts.secs());
+        ControlFlow::Continue(())
+    })?;
+    if !finished {
+        return Ok(None);
+    }
+
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    let sha256 = format!("{:x}", hasher.finalize());
+    Ok(Some(Entry {
+        name: name.to_string(),
+        bytes,
+        items,
+        first: read::format_ts(first)?,
+        last: read::format_ts(last)?,
+        sha256,
+    }))
+}
@@ -0,0 +1,314 @@
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, fs};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+
+use crate::log::Level;
+use crate::name::{Collision, Sequence};
+use crate::read::{Binary, Precision};
+use crate::transform::Transform;
+use crate::{Durability, Oversize};
+
+/// Settings read from the environment at startup; anything unparseable stops us starting.
+pub struct Config {
+    /// where the event files (and everything else we keep) live; created if it's missing
+    pub data_dir: PathBuf,
+    /// refuse writes when the data dir's filesystem has less than this free; on its own, this
+    /// never frees anything up, so writes stay refused until someone does (see `evict`)
+    pub min_free_bytes: u64,
+    /// when there's less than `min_free_bytes` free, delete finished files, oldest first, until
+    /// there's enough again, as `retention::evict`; `BATCHY_EVICT`, off by default
+    pub evict: bool,
+    /// applied to every payload in `store`; see `Transform` for the syntax
+    pub transform: Transform,
+    /// responses smaller than this are never gzipped, whatever the client accepts
+    pub compress_min_bytes: u16,
+    /// log every item's size, and how much it grew the file by; very noisy
+    pub log_item_stats: bool,
+    /// what to do if a new file's name is already taken
+    pub name_collision: Collision,
+    /// finish any files left unfinished by a previous run before starting a new one
+    pub finish_on_startup: bool,
+    /// the least severe lines the background tasks (rotation, watchers) log; `off` for none
+    pub scheduler_log_level: Level,
+    /// the most events any read endpoint returns in one response, whatever `limit` it's given
+    pub max_read_items: u64,
+    /// how the text read-back endpoints show payloads which aren't UTF-8, unless asked
+    pub binary: Binary,
+    /// open the circuit breaker after this many failed writes in the window; 0 never does
+    pub breaker_failures: usize,
+    /// how far back the breaker counts failures
+    pub breaker_window: Duration,
+    /// how long an open breaker refuses writes before it tries one
+    pub breaker_cooldown: Duration,
+    /// also write every file here, e.g. on another disk; failures only disable the copy
+    pub mirror_dir: Option<PathBuf>,
+    /// if `BATCHY_SEQUENCE` is on, the counter for files' sequence numbers; see `Sequence`
+    pub sequence: Option<Sequence>,
+    /// what to do with the rest of a body which is too long; see `Oversize`
+    pub oversize: Oversize,
+    /// also listen on a unix socket here, e.g. for a producer in the same pod
+    pub uds_path: Option<PathBuf>,
+    /// only listen on `uds_path`, not TCP
+    pub uds_only: bool,
+    /// keep this many of the latest events in memory, for `/api/tail`; each is up to
+    /// `max_body_bytes`
+    pub recent_buffer: usize,
+    /// give up on a `/store` which hasn't responded within this (default 10s), e.g. stuck
+    /// behind a slow disk, with a 503 the client can retry on; `None` (i.e. `0`) waits forever
+    pub store_timeout: Option<Duration>,
+    /// the same for the read endpoints (default 300s), which can legitimately take a while to
+    /// scan a big file; only until they start responding, not for the whole download
+    pub read_timeout: Option<Duration>,
+    /// refuse `/store`s with any other `Content-Type` (ignoring parameters like `charset`), with
+    /// a 415; this only checks the label, `BATCHY_TRANSFORM` is what checks JSON parses
+    pub require_content_type: Option<String>,
+    /// what separates the records in a `/store/archive` upload, after unescaping `\n`, `\r`,
+    /// `\t`, `\0` and `\\`; a newline by default
+    pub archive_delimiter: Vec<u8>,
+    /// how far a stored item gets before we respond, unless the client asks for more; see
+    /// `Durability`
+    pub durability: Durability,
+    /// the `Retry-After`, in seconds, on every 503 (paused, out of space, timed out, ...)
+    pub retry_after: u64,
+    /// keep a `catalog.jsonl` of the finished files up to date; see `catalog::maintain`
+    pub catalog: bool,
+    /// reserve this much disk for each new file, so it's less fragmented, and give back what's
+    /// unused when it's finished; Linux only. The space counts as used (e.g. against quotas,
+    /// and `BATCHY_MIN_FREE_BYTES`) meanwhile
+    pub prealloc_bytes: u64,
+    /// start a new file this often, a day by default; `BATCHY_CYCLE_INTERVAL` is e.g. `1h` or
+    /// `7days`, but `BATCHY_CYCLE_SECS` is still understood
+    pub cycle_interval: Duration,
+    /// where to listen for TCP, `BATCHY_BIND` (an IPv4 or IPv6 address, or a socket address
+    /// like `[::1]:3001`) and `BATCHY_PORT`; everywhere, on port 3000, by default
+    pub listen: SocketAddr,
+    /// the longest item we'll take, in bytes, as a `/store` body (or `?data=`), or a part or
+    /// record of a bulk upload; 4MiB by default
+    pub max_body_bytes: usize,
+    /// start a new file once the live one is this big on disk, compressed; 0 (the default) means
+    /// no limit. Checked after each store, so files end up a little over this
+    pub max_file_bytes: u64,
+    /// start a new file once the live one has this many events, so downstream jobs get even
+    /// chunks; 0 (the default) means no limit
+    pub max_events: u64,
+    /// delete finished files started longer ago than this, `BATCHY_RETENTION` (e.g. `30days`)
+    /// or `BATCHY_RETENTION_SECS`; `None` (i.e. `0`, the default) keeps everything. See
+    /// `retention::expire`
+    pub retention: Option<Duration>,
+    /// record each `/store`'s `Content-Type` with its event, so consumers can tell JSON from
+    /// protobuf; this changes the framing of new files' events, see `read::TYPED_TS`
+    pub keep_content_type: bool,
+    /// the zstd level for new files (and their mirrors), 1 to 22; `None` is archiv's default
+    pub compression_level: Option<i32>,
+    /// the unit of new files' timestamps, `seconds` (the default), `millis` or `nanos`; the
+    /// read endpoints still talk in seconds, with an extra `ts_nanos` where there's more
+    pub timestamp_precision: Precision,
+    /// how many live files to write at once, each with its own lock, so concurrent stores
+    /// needn't queue for one; 1 by default. Events are only in order within each file, and
+    /// reads by time can't skip files, as files' names no longer say where earlier ones end
+    pub shards: usize,
+    /// if set, every request, except for the probes, `/metrics` and `/api/format`, must
+    /// have an `Authorization: Bearer` header with this in it; see `auth::require_token`
+    pub token: Option<String>,
+    /// carry on writing to a file a previous run didn't finish, instead of starting a new one,
+    /// if its name says it was started within this, `BATCHY_RESUME_WITHIN` (e.g. `10min`);
+    /// `None` (i.e. `0`, the default) always starts afresh. See `admin::resume_leftovers`
+    pub resume_within: Option<Duration>,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Config> {
+        let data_dir = env::var_os("BATCHY_DATA_DIR")
+            .filter(|v| !v.is_empty())
+            .map_or_else(|| PathBuf::from("."), PathBuf::from);
+        fs::create_dir_all(&data_dir)
+            .with_context(|| format!("creating BATCHY_DATA_DIR={data_dir:?}"))?;
+        let config = Config {
+            min_free_bytes: env_or("BATCHY_MIN_FREE_BYTES", 0)?,
+            evict: env_flag("BATCHY_EVICT")?,
+            transform: env_or("BATCHY_TRANSFORM", Transform::default())?,
+            compress_min_bytes: env_or("BATCHY_COMPRESS_MIN_BYTES", 1024)?,
+            log_item_stats: env_flag("BATCHY_LOG_ITEM_STATS")?,
+            name_collision: env_or("BATCHY_NAME_COLLISION", Collision::Error)?,
+            finish_on_startup: env_flag("BATCHY_FINISH_ON_STARTUP")?,
+            scheduler_log_level: env_or("BATCHY_SCHEDULER_LOG_LEVEL", Level::Info)?,
+            max_read_items: env_or("BATCHY_MAX_READ_ITEMS", 10_000)?,
+            binary: env_or("BATCHY_BINARY", Binary::Base64)?,
+            breaker_failures: env_or("BATCHY_BREAKER_FAILURES", 10)?,
+            breaker_window: Duration::from_secs(env_or("BATCHY_BREAKER_WINDOW_SECS", 60)?),
+            breaker_cooldown: Duration::from_secs(env_or("BATCHY_BREAKER_COOLDOWN_SECS", 30)?),
+            mirror_dir: env::var_os("BATCHY_MIRROR_DIR")
+                .filter(|v| !v.is_empty())
+                .map(PathBuf::from),
+            sequence: match env_flag("BATCHY_SEQUENCE")? {
+                true => Some(Sequence::load(&data_dir)?),
+                false => None,
+            },
+            oversize: env_or("BATCHY_OVERSIZE_BEHAVIOR", Oversize::Drain)?,
+            uds_path: env::var_os("BATCHY_UDS_PATH")
+                .filter(|v| !v.is_empty())
+                .map(PathBuf::from),
+            uds_only: env_flag("BATCHY_UDS_ONLY")?,
+            recent_buffer: env_or("BATCHY_RECENT_BUFFER", 1000)?,
+            store_timeout: env_timeout("BATCHY_STORE_TIMEOUT_SECS", 10)?,
+            read_timeout: env_timeout("BATCHY_READ_TIMEOUT_SECS", 300)?,
+            require_content_type: env::var("BATCHY_REQUIRE_CONTENT_TYPE")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .map(|v| v.to_ascii_lowercase()),
+            archive_delimiter: match env::var("BATCHY_ARCHIVE_DELIMITER") {
+                Ok(val) => unescape(&val)
+                    .ok_or_else(|| anyhow!("invalid BATCHY_ARCHIVE_DELIMITER={val:?}"))?,
+                Err(env::VarError::NotPresent) => b"\n".to_vec(),
+                Err(err) => bail!("invalid BATCHY_ARCHIVE_DELIMITER: {err}"),
+            },
+            durability: env_or("BATCHY_DURABILITY", Durability::Flushed)?,
+            retry_after: env_or("BATCHY_RETRY_AFTER_SECS", 5)?,
+            catalog: env_flag("BATCHY_CATALOG")?,
+            prealloc_bytes: env_or("BATCHY_PREALLOC_BYTES", 0)?,
+            cycle_interval: cycle_interval()?,
+            data_dir,
+            listen: listen()?,
+            max_body_bytes: env_or("BATCHY_MAX_BODY", 4 * 1024 * 1024)?,
+            max_file_bytes: env_or("BATCHY_MAX_FILE_BYTES", 0)?,
+            max_events: env_or("BATCHY_MAX_EVENTS", 0)?,
+            retention: retention()?,
+            keep_content_type: env_flag("BATCHY_KEEP_CONTENT_TYPE")?,
+            timestamp_precision: env_or("BATCHY_TIMESTAMP_PRECISION", Precision::Seconds)?,
+            compression_level: match env::var_os("BATCHY_COMPRESSION_LEVEL") {
+                Some(_) => Some(env_or("BATCHY_COMPRESSION_LEVEL", 0)?),
+                None => None,
+            },
+            shards: env_or("BATCHY_SHARDS", 1)?,
+            token: env::var("BATCHY_TOKEN").ok().filter(|v| !v.is_empty()),
+            resume_within: Some(
+                env_or(
+                    "BATCHY_RESUME_WITHIN",
+                    humantime::Duration::from(Duration::ZERO),
+                )?
+                .into(),
+            )
+            .filter(|within: &Duration| !within.is_zero()),
+        };
+        if let Some(level) = config.compression_level {
+            if !(1..=22).contains(&level) {
+                bail!("BATCHY_COMPRESSION_LEVEL must be between 1 and 22, not {level}");
+            }
+        }
+        if config.shards == 0 {
+            bail!("BATCHY_SHARDS must be at least 1");
+        }
+        if config.cycle_interval.is_zero() {
+            bail!("the rotation interval must be more than zero");
+        }
+        if config.uds_only && config.uds_path.is_none() {
+            bail!("BATCHY_UDS_ONLY needs a BATCHY_UDS_PATH");
+        }
+        Ok(config)
+    }
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> Result<T>
+where
+    T::Err: std::fmt::Display,
+{
+    match env::var(name) {
+        Ok(val) => val
+            .parse()
+            .map_err(|err| anyhow!("invalid {name}={val:?}: {err}")),
+        Err(env::VarError::NotPresent) => Ok(default),
+        Err(err) => Err(anyhow!("invalid {name}: {err}")),
+    }
+}
+
+/// `\n`-style escapes, for things which are awkward to put in an environment variable; `None`
+/// for an escape we don't know, or an empty result.
+fn unescape(val: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut chars = val.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                '0' => '\0',
+                '\\' => '\\',
+                _ => return None,
+            },
+            c => c,
+        };
+        out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+    }
+    Some(out).filter(|out| !out.is_empty())
+}
+
+fn listen() -> Result<SocketAddr> {
+    let port = match env::var_os("BATCHY_PORT") {
+        Some(_) => Some(env_or("BATCHY_PORT", 3000)?),
+        None => None,
+    };
+    let bind = env::var("BATCHY_BIND").unwrap_or_default();
+    if let Ok(addr) = bind.parse::<SocketAddr>() {
+        if port.is_some() {
+            bail!("BATCHY_BIND={bind:?} already has a port, so BATCHY_PORT can't be used");
+        }
+        return Ok(addr);
+    }
+    let ip = env_or("BATCHY_BIND", IpAddr::V6(Ipv6Addr::UNSPECIFIED))?;
+    Ok(SocketAddr::new(ip, port.unwrap_or(3000)))
+}
+
+fn cycle_interval() -> Result<Duration> {
+    let day = Duration::from_secs(24 * 60 * 60);
+    if env::var_os("BATCHY_CYCLE_INTERVAL").is_some() {
+        if env::var_os("BATCHY_CYCLE_SECS").is_some() {
+            bail!("only one of BATCHY_CYCLE_INTERVAL and BATCHY_CYCLE_SECS, please");
+        }
+        return Ok(env_or("BATCHY_CYCLE_INTERVAL", humantime::Duration::from(day))?.into());
+    }
+    Ok(Duration::from_secs(env_or(
+        "BATCHY_CYCLE_SECS",
+        day.as_secs(),
+    )?))
+}
+
+fn retention() -> Result<Option<Duration>> {
+    if env::var_os("BATCHY_RETENTION").is_none() {
+        return env_timeout("BATCHY_RETENTION_SECS", 0);
+    }
+    if env::var_os("BATCHY_RETENTION_SECS").is_some() {
+        bail!("only one of BATCHY_RETENTION and BATCHY_RETENTION_SECS, please");
+    }
+    let keep: Duration = env_or(
+        "BATCHY_RETENTION",
+        humantime::Duration::from(Duration::ZERO),
+    )?
+    .into();
+    Ok(Some(keep).filter(|keep| !keep.is_zero()))
+}
+
+/// A number of seconds, where `0` means none at all, e.g. no timeout.
+fn env_timeout(name: &str, default_secs: u64) -> Result<Option<Duration>> {
+    Ok(match env_or(name, default_secs)? {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    })
+}
+
+/// Unset, `0` or `false` are off; `1` or `true` are on.
+fn env_flag(name: &str) -> Result<bool> {
+    match env::var(name) {
+        Ok(val) => match val.as_str() {
+            "1" | "true" => Ok(true),
+            "0" | "false" | "" => Ok(false),
+            _ => Err(anyhow!("invalid {name}={val:?}: expected 1 or 0")),
+        },
+        Err(env::VarError::NotPresent) => Ok(false),
+        Err(err) => Err(anyhow!("invalid {name}: {err}")),
+    }
+}
@@ -0,0 +1,92 @@
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use bunyarrs::{vars, vars_dbg};
+
+use crate::{retention, Output};
+
+/// Space available to us (i.e. not counting root's reserve) on the filesystem holding `path`.
+#[cfg(unix)]
+pub fn free_bytes(path: &Path) -> Result<u64> {
+    let stat = nix::sys::statvfs::statvfs(path)?;
+    #[allow(clippy::useless_conversion)] // the widths vary by platform
+    let free = u64::from(stat.blocks_available()) * u64::from(stat.fragment_size());
+    Ok(free)
+}
+
+#[cfg(not(unix))]
+pub fn free_bytes(_path: &Path) -> Result<u64> {
+    Ok(u64::MAX)
+}
+
+/// Reserve `bytes` for the (empty) file up front, without changing its length, so it's more
+/// likely to be contiguous on disk. Only on Linux; elsewhere, this does nothing.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn preallocate(file: &fs::File, bytes: u64) -> Result<()> {
+    use std::os::unix::io::AsRawFd as _;
+
+    use nix::fcntl::{fallocate, FallocateFlags};
+
+    fallocate(
+        file.as_raw_fd(),
+        FallocateFlags::FALLOC_FL_KEEP_SIZE,
+        0,
+        bytes.try_into()?,
+    )?;
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn preallocate(_file: &fs::File, _bytes: u64) -> Result<()> {
+    Ok(())
+}
+
+/// Give back whatever `preallocate` reserved which the file didn't use: truncating to its
+/// current length frees the blocks past the end.
+pub fn release_preallocated(file: &fs::File) -> Result<()> {
+    file.set_len(file.metadata()?.len())?;
+    Ok(())
+}
+
+/// Keep `Output::free_bytes` fresh, so `store` doesn't have to ask the OS every time, and, with
+/// `BATCHY_EVICT`, make room when it's low.
+pub async fn watch_free_space(output: Arc<Output>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    let min_free_bytes = output.config.min_free_bytes;
+    let mut was_low = false;
+
+    loop {
+        interval.tick().await;
+
+        let free_bytes = match free_bytes(&output.config.data_dir) {
+            Ok(free_bytes) if free_bytes < min_free_bytes && output.config.evict => {
+                retention::evict(&output, free_bytes).await
+            }
+            Ok(free_bytes) => free_bytes,
+            Err(err) => {
+                output
+                    .scheduler_logger
+                    .warn(vars_dbg!(err), "unable to check free disk space");
+                continue;
+            }
+        };
+        output.free_bytes.store(free_bytes, Ordering::Relaxed);
+
+        let low = free_bytes < min_free_bytes;
+        if low && !was_low {
+            output.scheduler_logger.error(
+                vars!(free_bytes, min_free_bytes),
+                "disk space low, refusing writes",
+            );
+        } else if !low && was_low {
+            output
+                .scheduler_logger
+                .info(vars!(free_bytes, min_free_bytes), "disk space recovered");
+        }
+        was_low = low;
+    }
+}
@@ -0,0 +1,103 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use anyhow::Result;
+use axum::Router;
+use bunyarrs::vars;
+use tokio::sync::watch;
+
+use crate::log::Logger;
+use crate::shutdown;
+
+/// Serve on TCP, and/or a unix socket, until we're asked to stop, then wait for both to finish
+/// their graceful shutdown.
+pub async fn serve(
+    logger: &Logger,
+    app: Router,
+    tcp: Option<SocketAddr>,
+    uds_path: Option<&Path>,
+) -> Result<()> {
+    // the signal only arrives once, but every listener needs to hear about it
+    let (stop, stopping) = watch::channel(());
+    tokio::spawn(async move {
+        shutdown::shutdown_signal().await;
+        let _ = stop.send(());
+    });
+    let stopped = |mut stopping: watch::Receiver<()>| async move {
+        let _ = stopping.changed().await;
+    };
+
+    let service = app.into_make_service();
+    let tcp = async {
+        let Some(addr) = tcp else {
+            return Ok(());
+        };
+        let server = axum::Server::try_bind(&addr)?.serve(service.clone());
+        // what was actually chosen, e.g. if asked for port 0
+        let listen = server.local_addr().to_string();
+        logger.info(vars!(listen), "server starting");
+        server
+            .with_graceful_shutdown(stopped(stopping.clone()))
+            .await?;
+        Ok::<_, anyhow::Error>(())
+    };
+    let uds = async {
+        match uds_path {
+            Some(path) => serve_uds(logger, path, service.clone(), stopped(stopping.clone())).await,
+            None => Ok(()),
+        }
+    };
+    tokio::try_join!(tcp, uds)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn serve_uds(
+    logger: &Logger,
+    path: &Path,
+    service: axum::routing::IntoMakeService<Router>,
+    stopped: impl Future<Output = ()>,
+) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt as _;
+    use std::{fs, io};
+
+    use bunyarrs::vars_dbg;
+    use tokio::net::UnixListener;
+
+    // left over from a previous run; refuse to remove anything else, though
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Ok(_) => anyhow::bail!("refusing to replace {path:?}, which isn't a socket"),
+        Err(err) => return Err(err.into()),
+    }
+    let listener = UnixListener::bind(path)?;
+    let uds_path = path.display().to_string();
+    logger.info(vars!(uds_path), "server starting");
+
+    let accept = hyper::server::accept::poll_fn(move |cx| {
+        listener
+            .poll_accept(cx)
+            .map(|accepted| Some(accepted.map(|(stream, _)| stream)))
+    });
+    axum::Server::builder(accept)
+        .serve(service)
+        .with_graceful_shutdown(stopped)
+        .await?;
+
+    if let Err(err) = fs::remove_file(path) {
+        logger.warn(vars_dbg!(err), "unable to remove unix socket");
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn serve_uds(
+    _logger: &Logger,
+    _path: &Path,
+    _service: axum::routing::IntoMakeService<Router>,
+    _stopped: impl Future<Output = ()>,
+) -> Result<()> {
+    anyhow::bail!("unix sockets aren't supported on this platform")
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bunyarrs::{vars, Bunyarr};
+use serde_json::Value;
+
+// lines beyond this are dropped (and counted) instead of blocking the caller
+const QUEUE_LEN: usize = 4096;
+
+static QUEUE: OnceLock<mpsc::SyncSender<Message>> = OnceLock::new();
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+enum Message {
+    Line {
+        name: Arc<str>,
+        level: Level,
+        extras: Value,
+        event_type: &'static str,
+    },
+    Flush(mpsc::Sender<()>),
+}
+
+/// Ordered by severity; a logger drops lines below its threshold, and `Off` drops everything.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+    Off,
+}
+
+impl FromStr for Level {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "info" => Level::Info,
+            "warn" => Level::Warn,
+            "error" => Level::Error,
+            "off" => Level::Off,
+            _ => return Err("expected one of: info, warn, error, off"),
+        })
+    }
+}
+
+/// Anything the handlers used to hand to `Bunyarr` as extras.
+pub trait Fields {
+    fn into_value(self) -> Value;
+}
+
+impl Fields for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl Fields for () {
+    fn into_value(self) -> Value {
+        Value::Object(Default::default())
+    }
+}
+
+/// A `Bunyarr` lookalike which never blocks.
+///
+/// `Bunyarr` writes straight to a locked stdout, so a full pipe would stall whoever is logging,
+/// which is often someone holding the writer lock. Instead, lines are queued to a dedicated
+/// thread, and dropped (with a count, reported later) if that thread can't keep up.
+pub struct Logger {
+    name: Arc<str>,
+    threshold: Level,
+}
+
+impl Logger {
+    pub fn with_name(name: impl ToString) -> Logger {
+        Logger::with_threshold(name, Level::Info)
+    }
+
+    pub fn with_threshold(name: impl ToString, threshold: Level) -> Logger {
+        Logger {
+            name: name.to_string().into(),
+            threshold,
+        }
+    }
+
+    pub fn info(&self, extras: impl Fields, event_type: &'static str) {
+        self.log(Level::Info, extras, event_type)
+    }
+
+    pub fn warn(&self, extras: impl Fields, event_type: &'static str) {
+        self.log(Level::Warn, extras, event_type)
+    }
+
+    pub fn error(&self, extras: impl Fields, event_type: &'static str) {
+        self.log(Level::Error, extras, event_type)
+    }
+
+    fn log(&self, level: Level, extras: impl Fields, event_type: &'static str) {
+        if level < self.threshold {
+            return;
+        }
+        let line = Message::Line {
+            name: Arc::clone(&self.name),
+            level,
+            extras: extras.into_value(),
+            event_type,
+        };
+        // if the thread has gone away, there's nobody left to tell
+        if let Err(mpsc::TrySendError::Full(_)) = queue().try_send(line) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Wait (briefly) for everything logged so far to be written, e.g. before exiting.
+pub fn flush() {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let (tx, rx) = mpsc::channel();
+    let mut msg = Message::Flush(tx);
+    loop {
+        match queue().try_send(msg) {
+            Ok(()) => break,
+            Err(mpsc::TrySendError::Full(back)) if Instant::now() < deadline => {
+                msg = back;
+                thread::sleep(Duration::from_millis(10));
+            }
+            Err(_) => return,
+        }
+    }
+    let _ = rx.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+}
+
+fn queue() -> &'static mpsc::SyncSender<Message> {
+    QUEUE.get_or_init(|| {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+        thread::Builder::new()
+            .name("batchy-log".to_string())
+            .spawn(move || drain(rx))
+            .expect("spawning log thread");
+        tx
+    })
+}
+
+fn drain(rx: mpsc::Receiver<Message>) {
+    let ours = Bunyarr::with_name("batchy-log");
+    let mut loggers = HashMap::<Arc<str>, Bunyarr>::new();
+    for msg in rx {
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped != 0 {
+            ours.warn(vars!(dropped), "log queue full, lines dropped");
+        }
+
+        match msg {
+            Message::Line {
+                name,
+                level,
+                extras,
+                event_type,
+            } => {
+                let logger = loggers
+                    .entry(Arc::clone(&name))
+                    .or_insert_with(|| Bunyarr::with_name(&*name));
+                match level {
+                    Level::Info => logger.info(extras, event_type),
+                    Level::Warn => logger.warn(extras, event_type),
+                    Level::Error => logger.error(extras, event_type),
+                    Level::Off => (),
+                }
+            }
+            Message::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
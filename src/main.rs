@@ -1,162 +1,1404 @@
 mod admin;
+mod auth;
+mod breaker;
+mod catalog;
+mod config;
+mod disk;
+mod listen;
+mod log;
+mod metrics;
+mod name;
+mod read;
+mod retention;
 mod shutdown;
+mod transform;
 
+use std::collections::{HashMap, VecDeque};
 use std::fs;
 use std::future::Future;
-use std::io::Write;
-use std::net::Ipv6Addr;
+use std::io::{Read as _, Seek as _, Write};
+use std::ops::{ControlFlow, Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use archiv::{Compress, CompressOptions, CompressStream};
-use axum::body::Bytes;
-use axum::extract::State;
-use axum::http::StatusCode;
-use axum::{Json, Router};
-use bunyarrs::{vars, vars_dbg, Bunyarr};
+use axum::body::{Body, Bytes, HttpBody as _};
+use axum::error_handling::HandleErrorLayer;
+use axum::extract::{DefaultBodyLimit, Multipart, Query, RawBody, State};
+use axum::http::header::{CONNECTION, CONTENT_TYPE, RETRY_AFTER};
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware;
+use axum::response::{IntoResponse, Response};
+use axum::routing::MethodRouter;
+use axum::{BoxError, Json, Router};
+use bunyarrs::{vars, vars_dbg};
+use serde::Deserialize;
 use serde_json::json;
 use serde_json::Value;
-use time::format_description::well_known::Rfc3339;
 use time::OffsetDateTime;
 use tokio::sync;
+use tower::ServiceBuilder;
+use tower_http::compression::predicate::{NotForContentType, Predicate as _, SizeAbove};
+use tower_http::compression::CompressionLayer;
 
 use admin::*;
+use breaker::{Breaker, Transition};
+use config::Config;
+use log::Logger;
 
 struct Writer {
     inner: CompressStream<'static, fs::File>,
+    // the file's name, which is in `BATCHY_DATA_DIR`, at `path`
     name: String,
+    path: PathBuf,
+    created: OffsetDateTime,
+    items: u64,
+    // a copy of `inner` in `BATCHY_MIRROR_DIR`, or None if it's off, or has failed
+    mirror: Option<CompressStream<'static, fs::File>>,
+    // set if someone panicked while holding the lock, so were maybe halfway through an item
+    suspect: bool,
+    // space was reserved past the end of the file, by `BATCHY_PREALLOC_BYTES`
+    preallocated: bool,
+    // events carry their content types, by `BATCHY_KEEP_CONTENT_TYPE`; see `read::TYPED_TS`
+    typed: bool,
+    // the unit of the events' timestamps, by `BATCHY_TIMESTAMP_PRECISION`; see
+    // `read::PRECISION_TS`
+    precision: read::Precision,
 }
 
-pub struct Output {
+impl Writer {
+    /// Finish the file (not its mirror), returning it for anyone who wants to sync it.
+    fn finish(self) -> Result<fs::File> {
+        let file = self.inner.finish()?;
+        if self.preallocated {
+            disk::release_preallocated(&file)?;
+        }
+        Ok(file)
+    }
+
+    /// Why the writer can't be trusted with any more items, if it can't: it was being used when
+    /// something panicked, or the file has gone wrong underneath it.
+    fn problem(&mut self) -> Option<String> {
+        if self.suspect {
+            return Some("a previous holder panicked".to_string());
+        }
+        let file = self.inner.get_mut();
+        let len = match file.metadata() {
+            Ok(meta) => meta.len(),
+            Err(err) => return Some(format!("unable to stat the file: {err}")),
+        };
+        // we only ever append, so we should be exactly at the end
+        match file.stream_position() {
+            Ok(pos) if pos == len => None,
+            Ok(pos) => Some(format!("position {pos} isn't the end of the file, {len}")),
+            Err(err) => Some(format!("unable to find the position: {err}")),
+        }
+    }
+
+    /// Copy an item to the mirror, if there is one. Failing is logged, not returned: the mirror
+    /// is abandoned until the next file, and the primary carries on.
+    fn mirror(&mut self, logger: &Logger, item: &[&[u8]]) {
+        if let Some(mirror) = self.mirror.as_mut() {
+            if let Err(err) = write(mirror, item).and_then(|()| Ok(mirror.flush()?)) {
+                let file_name = &self.name;
+                logger.error(
+                    vars_dbg!(file_name, err),
+                    "unable to write to mirror, abandoning it for this file",
+                );
+                self.mirror = None;
+            }
+        }
+    }
+
+    /// Finish the mirror (if we still have one); failing is only logged, as for writes.
+    fn finish_mirror(&mut self, logger: &Logger) {
+        if let Some(mirror) = self.mirror.take() {
+            if let Err(err) = mirror.finish() {
+                let file_name = &self.name;
+                logger.warn(vars_dbg!(file_name, err), "unable to finish mirror");
+            }
+        }
+    }
+}
+
+/// One of the `BATCHY_SHARDS` live files, with its own lock.
+struct Shard {
     // None means we're in some kind of error state, either shutting down,
-    // or unable to create a new file
-    out: Arc<sync::Mutex<Option<Writer>>>,
-    logger: Bunyarr,
+    // or unable to create a new file; go through `Output::lock_writer`
+    out: sync::Mutex<Option<Writer>>,
+    // a copy of the live writer's name, maintained by `WriterGuard`
+    live_file_name: std::sync::RwLock<Option<Arc<str>>>,
+    // and how many events are in it, for the healthcheck
+    live_items: AtomicU64,
+}
+
+impl Shard {
+    fn new(writer: Writer) -> Shard {
+        Shard {
+            live_file_name: std::sync::RwLock::new(Some(Arc::from(writer.name.as_str()))),
+            live_items: AtomicU64::new(writer.items),
+            out: sync::Mutex::new(Some(writer)),
+        }
+    }
+
+    /// The live file's name, as of the last time someone released the writer lock. Doesn't
+    /// take the lock, so probes and listings don't queue up behind `store`.
+    fn live_file_name(&self) -> Option<Arc<str>> {
+        self.live_file_name
+            .read()
+            .expect("not poisoned")
+            .as_ref()
+            .map(Arc::clone)
+    }
+}
+
+pub struct Output {
+    // at least one; every store goes to just one of them, see `lock_any_writer`
+    shards: Vec<Shard>,
+    // where `lock_any_writer` starts looking next
+    next_shard: AtomicUsize,
+    logger: Logger,
+    // for the background tasks, so their verbosity can be controlled separately
+    scheduler_logger: Logger,
+    // set by /api/drain; we keep accepting writes, but tell the load balancer to go away
+    draining: AtomicBool,
+    // set by /api/pause, for maintenance; store refuses everything, but the writer stays open
+    paused: AtomicBool,
+    // refreshed periodically by `disk::watch_free_space`
+    free_bytes: AtomicU64,
+    // unix time `time_based_cycle` next expects to fire, or 0 before it has started
+    next_time_cycle: AtomicI64,
+    // file name -> (modification time, uncompressed size), for `read::ratio` and `read::size`
+    ratio_cache: std::sync::Mutex<HashMap<PathBuf, (SystemTime, u64)>>,
+    // fed by `append`, and consulted before every store
+    breaker: Breaker,
+    // the last `BATCHY_RECENT_BUFFER` events, oldest first, across rotations, for `/api/tail`
+    recent: std::sync::Mutex<VecDeque<(read::Timestamp, Bytes)>>,
+    // poked by `WriterGuard` when the live file changes, i.e. one has been finished
+    catalog_stale: sync::Notify,
+    // for the healthcheck's uptime
+    started: Instant,
+    // for `/metrics`
+    metrics: metrics::Counters,
+    config: Config,
+}
+
+impl Output {
+    /// Take a shard's writer lock; any change to its live file is published when it's released.
+    ///
+    /// A writer in a bad way (see `Writer::problem`) is finished, as well as it can be, and
+    /// taken away, so the lock's holder will start a new file, like after a failed write.
+    async fn lock_writer<'o>(&'o self, shard: &'o Shard) -> WriterGuard<'o> {
+        self.checked(shard, shard.out.lock().await)
+    }
+
+    /// Take the lock of whichever shard is free, starting from a different one each time, so
+    /// they all get used; if they're all busy, wait for the one we started from.
+    async fn lock_any_writer(&self) -> WriterGuard<'_> {
+        let start = self.next_shard.fetch_add(1, Ordering::Relaxed);
+        let shards = self.shards.len();
+        for i in 0..shards {
+            let shard = &self.shards[(start + i) % shards];
+            if let Ok(guard) = shard.out.try_lock() {
+                return self.checked(shard, guard);
+            }
+        }
+        self.lock_writer(&self.shards[start % shards]).await
+    }
+
+    /// Wrap a shard's lock in a `WriterGuard`, dealing with any problem, as `lock_writer` says.
+    fn checked<'o>(
+        &'o self,
+        shard: &'o Shard,
+        guard: sync::MutexGuard<'o, Option<Writer>>,
+    ) -> WriterGuard<'o> {
+        let mut guard = WriterGuard {
+            guard,
+            shard,
+            cycles: &self.metrics.cycles,
+            catalog_stale: &self.catalog_stale,
+        };
+        if let Some(problem) = guard.as_mut().and_then(Writer::problem) {
+            let mut writer = guard.take().expect("just checked");
+            let file_name = &writer.name;
+            self.logger.error(
+                vars!(file_name, problem),
+                "writer inconsistent, finishing it and starting again",
+            );
+            writer.finish_mirror(&self.logger);
+            let file_name = writer.name.clone();
+            if let Err(err) = writer.finish() {
+                self.logger.error(
+                    vars_dbg!(file_name, err),
+                    "unable to finish inconsistent file",
+                );
+            }
+        }
+        guard
+    }
+
+    /// Every shard's `live_file_name`, or None if any of them is without a writer.
+    fn live_file_names(&self) -> Option<Vec<Arc<str>>> {
+        self.shards.iter().map(Shard::live_file_name).collect()
+    }
+
+    /// Whether this path (in the data dir) is a live file, as `live_file_name`.
+    fn is_live(&self, path: &Path) -> bool {
+        let name = path.file_name().and_then(|n| n.to_str());
+        name.is_some()
+            && self
+                .shards
+                .iter()
+                .any(|shard| shard.live_file_name().as_deref() == name)
+    }
+}
+
+struct WriterGuard<'o> {
+    guard: sync::MutexGuard<'o, Option<Writer>>,
+    shard: &'o Shard,
+    cycles: &'o AtomicU64,
+    catalog_stale: &'o sync::Notify,
+}
+
+impl Deref for WriterGuard<'_> {
+    type Target = Option<Writer>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl DerefMut for WriterGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl Drop for WriterGuard<'_> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            if let Some(writer) = self.guard.as_mut() {
+                writer.suspect = true;
+            }
+        }
+        let items = self.guard.as_ref().map_or(0, |w| w.items);
+        self.shard.live_items.store(items, Ordering::Relaxed);
+        let current = self.guard.as_ref().map(|w| w.name.as_str());
+        let live_file_name = &self.shard.live_file_name;
+        // the overwhelmingly common case, which only needs a read lock
+        if live_file_name.read().expect("not poisoned").as_deref() == current {
+            return;
+        }
+        if current.is_some() {
+            self.cycles.fetch_add(1, Ordering::Relaxed);
+        }
+        *live_file_name.write().expect("not poisoned") = current.map(Arc::from);
+        self.catalog_stale.notify_one();
+    }
 }
 
-fn finish(logger: &Bunyarr, writer: &mut Option<Writer>) -> Result<()> {
-    if let Some(writer) = writer.take() {
-        writer.inner.finish()?;
-        logger.info(json!({ "file_name": writer.name }), "completed file");
+fn finish(logger: &Logger, writer: &mut Option<Writer>) -> Result<()> {
+    if let Some(mut writer) = writer.take() {
+        writer.finish_mirror(logger);
+        let file_name = writer.name.clone();
+        writer.finish()?;
+        logger.info(vars!(file_name), "completed file");
     }
     Ok(())
 }
 
 async fn okay_or_500<F: Future<Output = Result<Value>>>(
-    logger: &Bunyarr,
+    logger: &Logger,
     func: impl FnOnce() -> F,
 ) -> (StatusCode, Json<Value>) {
     match func().await {
         Ok(resp) => (StatusCode::OK, Json(resp)),
-        Err(err) => {
-            logger.error(vars_dbg!(err), "error handling request");
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({ "error": "internal server error "})),
-            )
+        Err(err) => internal_error(logger, err),
+    }
+}
+
+fn internal_error(logger: &Logger, err: anyhow::Error) -> (StatusCode, Json<Value>) {
+    logger.error(vars_dbg!(err), "error handling request");
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({ "error": "internal server error "})),
+    )
+}
+
+fn json_error(status: StatusCode, error: &'static str) -> (StatusCode, Json<Value>) {
+    (status, Json(json!({ "error": error })))
+}
+
+fn bad_request(error: &'static str) -> (StatusCode, Json<Value>) {
+    json_error(StatusCode::BAD_REQUEST, error)
+}
+
+#[derive(Deserialize)]
+struct StoreQuery {
+    data: Option<String>,
+    // `1` or `true` to read the item back off the disk before responding, which is slow
+    verify: Option<String>,
+    // `1` or `true` for the same as `X-Durable: true`
+    durable: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BulkQuery {
+    durable: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StoreForm {
+    data: String,
+}
+
+async fn store(
+    State(state): State<Arc<Output>>,
+    Query(query): Query<StoreQuery>,
+    headers: HeaderMap,
+    RawBody(body): RawBody,
+) -> Response {
+    if let Some(refusal) = refuse_stores(&state) {
+        return refusal;
+    }
+    if !content_type_allowed(&state, &headers) {
+        return json_error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported content type",
+        )
+        .into_response();
+    }
+    let max = state.config.max_body_bytes;
+    let buf = match read_capped(body, max, state.config.oversize).await {
+        Ok(buf) => buf,
+        Err(resp) => return resp,
+    };
+    // the raw body is the payload, unless the client can't manage that, and sends a form,
+    // or an empty body and a `?data=`
+    let is_form = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"));
+    let mut content_type = headers.get(CONTENT_TYPE).map_or(&b""[..], |v| v.as_bytes());
+    let buf = if is_form {
+        match serde_urlencoded::from_bytes::<StoreForm>(&buf) {
+            Ok(form) => {
+                // that described the form, not what was in it
+                content_type = b"";
+                Bytes::from(form.data)
+            }
+            Err(_) => return bad_request("form bodies must have a data field").into_response(),
         }
+    } else {
+        match query.data {
+            Some(data) if buf.is_empty() => {
+                content_type = b"";
+                Bytes::from(data)
+            }
+            _ => buf,
+        }
+    };
+    if state.config.keep_content_type && content_type.len() > usize::from(u8::MAX) {
+        return bad_request("content type too long").into_response();
+    }
+    if buf.len() > max {
+        return too_long(max, Some(buf.len())).into_response();
+    }
+    let buf = match transform(&state, buf) {
+        Ok(buf) => buf,
+        Err(error) => return bad_request(error).into_response(),
+    };
+    let now = state.config.timestamp_precision.now();
+    let verify = matches!(query.verify.as_deref(), Some("1" | "true"));
+    let mut durability = durability(&state, &headers, query.durable.as_deref());
+    if verify {
+        // there'd be nothing to read back
+        durability = durability.max(Durability::Flushed);
+    }
+    let header = file_header(&headers);
+
+    let event = Event {
+        ts: now,
+        content_type,
+        body: &buf,
+    };
+    match append(&state, &[event], header, durability, verify).await {
+        Ok(()) if verify => Json(json!({
+            "buffered": true,
+            "durability": durability.as_str(),
+            "verified": true,
+        }))
+        .into_response(),
+        Ok(()) => stored(match durability {
+            Durability::Buffered => STORED_BUFFERED,
+            Durability::Flushed => STORED_FLUSHED,
+            Durability::Synced => STORED_SYNCED,
+        }),
+        Err(err) => internal_error(&state.logger, err).into_response(),
     }
 }
 
-async fn store(State(state): State<Arc<Output>>, buf: Bytes) -> (StatusCode, Json<Value>) {
-    if buf.len() > 4 * 1024 * 1024 {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({ "error": "too long" })),
-        );
+/// Whether the body is labelled as `BATCHY_REQUIRE_CONTENT_TYPE` wants, if it wants anything.
+fn content_type_allowed(state: &Output, headers: &HeaderMap) -> bool {
+    let Some(required) = &state.config.require_content_type else {
+        return true;
+    };
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|essence| essence.trim().eq_ignore_ascii_case(required))
+}
+
+/// Store each part of a `multipart/form-data` body as its own item, for tools which naturally
+/// produce that (form submissions, batches of files).
+///
+/// Every part is read (and checked) before anything is written, so a rejected upload stores
+/// nothing. Part names and content types aren't kept, even with `BATCHY_KEEP_CONTENT_TYPE`.
+async fn store_multipart(
+    State(state): State<Arc<Output>>,
+    Query(query): Query<BulkQuery>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    if let Some(refusal) = refuse_stores(&state) {
+        return refusal;
     }
-    let now = OffsetDateTime::now_utc().unix_timestamp();
 
-    okay_or_500(&state.logger, || async {
-        let mut opt = state.out.lock().await;
-        if opt.is_none() {
-            opt.replace(new_file(&state.logger)?);
+    let mut parts = Vec::new();
+    let mut total = 0usize;
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(_) => return bad_request("invalid multipart body").into_response(),
+        };
+        let mut buf = Vec::new();
+        loop {
+            match field.chunk().await {
+                Ok(Some(chunk)) => buf.extend_from_slice(&chunk),
+                Ok(None) => break,
+                Err(_) => return bad_request("invalid multipart body").into_response(),
+            }
+            let max = state.config.max_body_bytes;
+            if buf.len() > max {
+                return item_too_long("part too long", max).into_response();
+            }
+        }
+        total += buf.len();
+        if total > MAX_MULTIPART_BYTES {
+            return too_long(MAX_MULTIPART_BYTES, None).into_response();
+        }
+        match transform(&state, Bytes::from(buf)) {
+            Ok(buf) => parts.push(buf),
+            Err(error) => return bad_request(error).into_response(),
+        }
+    }
+
+    let durability = durability(&state, &headers, query.durable.as_deref());
+    append_all(&state, &headers, durability, &parts).await
+}
+
+#[derive(Deserialize)]
+struct BatchQuery {
+    durable: Option<String>,
+    format: Option<String>,
+}
+
+/// Store many items from one body, for producers which send bursts, under one hold of the lock.
+///
+/// With `format=ndjson` (the default), each line is an item; lines may end in `\r\n`, and
+/// empty ones are skipped. With `format=frames`, the body is items each preceded by their
+/// length, as a little-endian `u32`, so they can contain anything. As with multipart, nothing is
+/// written unless every item is acceptable.
+async fn store_batch(
+    State(state): State<Arc<Output>>,
+    Query(query): Query<BatchQuery>,
+    headers: HeaderMap,
+    RawBody(body): RawBody,
+) -> Response {
+    if let Some(refusal) = refuse_stores(&state) {
+        return refusal;
+    }
+    let frames = match query.format.as_deref().unwrap_or("ndjson") {
+        "ndjson" => false,
+        "frames" => true,
+        _ => return bad_request("format must be one of: ndjson, frames").into_response(),
+    };
+    let buf = match read_capped(body, MAX_MULTIPART_BYTES, state.config.oversize).await {
+        Ok(buf) => buf,
+        Err(resp) => return resp,
+    };
+
+    let found = match frames {
+        true => match split_frames(&buf) {
+            Some(found) => found,
+            None => return bad_request("truncated frame").into_response(),
+        },
+        false => split(&buf, b"\n")
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .filter(|line| !line.is_empty())
+            .collect(),
+    };
+    let mut items = Vec::with_capacity(found.len());
+    for item in found {
+        let max = state.config.max_body_bytes;
+        if item.len() > max {
+            return item_too_long("item too long", max).into_response();
+        }
+        match transform(&state, buf.slice_ref(item)) {
+            Ok(buf) => items.push(buf),
+            Err(error) => return bad_request(error).into_response(),
+        }
+    }
+    let durability = durability(&state, &headers, query.durable.as_deref());
+    append_all(&state, &headers, durability, &items).await
+}
+
+/// The items of a `format=frames` batch, or `None` if the last one is cut short.
+fn split_frames(mut buf: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut items = Vec::new();
+    while !buf.is_empty() {
+        let (len, rest) = buf.split_first_chunk::<4>()?;
+        let len = usize::try_from(u32::from_le_bytes(*len)).ok()?;
+        if rest.len() < len {
+            return None;
+        }
+        let (item, rest) = rest.split_at(len);
+        items.push(item);
+        buf = rest;
+    }
+    Some(items)
+}
+
+/// Store each record of a gzip file (or many concatenated gzip members, as `cat` makes) as
+/// its own item, splitting on `BATCHY_ARCHIVE_DELIMITER`, for bulk imports of existing logs.
+///
+/// Empty records, e.g. after a trailing delimiter, are skipped. As with multipart, nothing is
+/// written unless every record is acceptable.
+async fn store_archive(
+    State(state): State<Arc<Output>>,
+    Query(query): Query<BulkQuery>,
+    headers: HeaderMap,
+    RawBody(body): RawBody,
+) -> Response {
+    if let Some(refusal) = refuse_stores(&state) {
+        return refusal;
+    }
+    let compressed = match read_capped(body, MAX_MULTIPART_BYTES, state.config.oversize).await {
+        Ok(buf) => buf,
+        Err(resp) => return resp,
+    };
+    let decompressed = tokio::task::spawn_blocking(move || {
+        // one more than the cap, so we can tell if it'd go over
+        let mut decoder =
+            flate2::read::MultiGzDecoder::new(&compressed[..]).take(MAX_ARCHIVE_BYTES as u64 + 1);
+        let mut buf = Vec::new();
+        decoder.read_to_end(&mut buf).map(|_| buf)
+    })
+    .await;
+    let decompressed = match decompressed {
+        Ok(Ok(buf)) if buf.len() > MAX_ARCHIVE_BYTES => {
+            return json_error(StatusCode::PAYLOAD_TOO_LARGE, "decompressed too long")
+                .into_response()
+        }
+        Ok(Ok(buf)) => buf,
+        Ok(Err(_)) => return bad_request("invalid gzip body").into_response(),
+        Err(err) => return internal_error(&state.logger, err.into()).into_response(),
+    };
+
+    let mut records = Vec::new();
+    for record in split(&decompressed, &state.config.archive_delimiter) {
+        if record.is_empty() {
+            continue;
+        }
+        let max = state.config.max_body_bytes;
+        if record.len() > max {
+            return item_too_long("record too long", max).into_response();
+        }
+        match transform(&state, Bytes::copy_from_slice(record)) {
+            Ok(buf) => records.push(buf),
+            Err(error) => return bad_request(error).into_response(),
+        }
+    }
+    let durability = durability(&state, &headers, query.durable.as_deref());
+    append_all(&state, &headers, durability, &records).await
+}
+
+fn split<'b>(mut buf: &'b [u8], delimiter: &'b [u8]) -> impl Iterator<Item = &'b [u8]> {
+    std::iter::from_fn(move || {
+        if buf.is_empty() {
+            return None;
+        }
+        let end = buf
+            .windows(delimiter.len())
+            .position(|window| window == delimiter);
+        let record;
+        (record, buf) = match end {
+            Some(end) => (&buf[..end], &buf[end + delimiter.len()..]),
+            None => (buf, &[][..]),
+        };
+        Some(record)
+    })
+}
+
+/// Store many items, as one request, for the bulk endpoints.
+async fn append_all(
+    state: &Output,
+    headers: &HeaderMap,
+    durability: Durability,
+    items: &[Bytes],
+) -> Response {
+    // the bulk formats don't have a content type for each item
+    let events = items
+        .iter()
+        .map(|item| Event {
+            ts: state.config.timestamp_precision.now(),
+            content_type: b"",
+            body: item,
+        })
+        .collect::<Vec<_>>();
+    let header = file_header(headers);
+    if let Err(err) = append(state, &events, header, durability, false).await {
+        return internal_error(&state.logger, err).into_response();
+    }
+    Json(json!({
+        "buffered": true,
+        "durability": durability.as_str(),
+        "items": items.len(),
+    }))
+    .into_response()
+}
+
+/// What a client sending a body over the limit sees.
+///
+/// `Drain` reads (and throws away) the rest of the body, so it gets a clean 413, and can reuse
+/// the connection; `Reset` responds immediately and drops the connection, which saves the
+/// bandwidth, but most clients report as a connection error, not the 413. Even under `Drain`,
+/// we give up after `MAX_DRAIN_BYTES`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Oversize {
+    Drain,
+    Reset,
+}
+
+impl FromStr for Oversize {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "drain" => Oversize::Drain,
+            "reset" => Oversize::Reset,
+            _ => return Err("expected one of: drain, reset"),
+        })
+    }
+}
+
+const MAX_DRAIN_BYTES: usize = 64 * 1024 * 1024;
+
+/// Read the whole body, unless it's longer than `cap`, in which case, the response to send.
+async fn read_capped(mut body: Body, cap: usize, oversize: Oversize) -> Result<Bytes, Response> {
+    let mut buf = Vec::new();
+    let mut received = 0usize;
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(_) => return Err(bad_request("unable to receive body").into_response()),
+        };
+        received += chunk.len();
+        if received <= cap {
+            buf.extend_from_slice(&chunk);
+            continue;
+        }
+        if oversize == Oversize::Reset || received > MAX_DRAIN_BYTES {
+            // we didn't wait to see how long it really was
+            return Err(([(CONNECTION, "close")], too_long(cap, None)).into_response());
+        }
+    }
+    match received > cap {
+        true => Err(too_long(cap, Some(received)).into_response()),
+        false => Ok(Bytes::from(buf)),
+    }
+}
+
+/// One of the items in a bulk upload is over `BATCHY_MAX_BODY`; still a 400, not a 413, as the
+/// upload as a whole might be fine.
+fn item_too_long(error: &'static str, limit: usize) -> (StatusCode, Json<Value>) {
+    let body = json!({ "error": error, "limit_bytes": limit });
+    (StatusCode::BAD_REQUEST, Json(body))
+}
+
+fn too_long(limit: usize, received: Option<usize>) -> (StatusCode, Json<Value>) {
+    let mut body = json!({ "error": "too long", "limit_bytes": limit });
+    if let Some(received) = received {
+        body["received_bytes"] = received.into();
+    }
+    (StatusCode::PAYLOAD_TOO_LARGE, Json(body))
+}
+
+const MAX_MULTIPART_BYTES: usize = 64 * 1024 * 1024;
+// after decompression; the compressed body is limited like a multipart one
+const MAX_ARCHIVE_BYTES: usize = 256 * 1024 * 1024;
+
+/// Whether we're refusing all stores for now, and what to tell the client if we are.
+fn refuse_stores(state: &Output) -> Option<Response> {
+    let error = if state.paused.load(Ordering::Relaxed) {
+        "ingestion paused"
+    } else if state.free_bytes.load(Ordering::Relaxed) < state.config.min_free_bytes {
+        "insufficient disk space"
+    } else if !state.breaker.allow(Instant::now()) {
+        "writes failing, circuit breaker open"
+    } else {
+        return None;
+    };
+    Some(unavailable(
+        state.config.retry_after,
+        json!({ "error": error }),
+    ))
+}
+
+/// A 503, with a `Retry-After` of `BATCHY_RETRY_AFTER_SECS`, so clients all back off alike.
+fn unavailable(retry_after: u64, body: Value) -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(RETRY_AFTER, retry_after.to_string())],
+        Json(body),
+    )
+        .into_response()
+}
+
+fn transform(state: &Output, buf: Bytes) -> Result<Bytes, &'static str> {
+    match state.config.transform.is_empty() {
+        true => Ok(buf),
+        false => state.config.transform.apply(&buf).map(Bytes::from),
+    }
+}
+
+/// How far an item has got when we respond, which is in the response as `"durability"`.
+///
+/// Each tier includes the ones before it. Whatever the tier, the item is in the file (or will
+/// be) before any item stored after it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Durability {
+    /// handed to the compressor, which may hold on to it (in memory) until a later store
+    /// flushes, or the file is finished; lost if we crash, but not if we're stopped
+    Buffered,
+    /// written to the file, so it survives us crashing, and readers can see it, but not the
+    /// machine crashing
+    Flushed,
+    /// written, and `fsync`ed (well, `fdatasync`ed), so it survives anything short of the disk
+    Synced,
+}
+
+impl Durability {
+    fn as_str(self) -> &'static str {
+        match self {
+            Durability::Buffered => "buffered",
+            Durability::Flushed => "flushed",
+            Durability::Synced => "synced",
         }
+    }
+}
+
+impl FromStr for Durability {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "buffered" => Durability::Buffered,
+            "flushed" => Durability::Flushed,
+            "synced" => Durability::Synced,
+            _ => return Err("expected one of: buffered, flushed, synced"),
+        })
+    }
+}
+
+/// `BATCHY_DURABILITY`, unless the client asks for its events to hit the disk before we
+/// respond, with `X-Durable: true` or `?durable=1`; it can't ask for less.
+fn durability(state: &Output, headers: &HeaderMap, query: Option<&str>) -> Durability {
+    let header = headers
+        .get("x-durable")
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"true"));
+    let query = matches!(query, Some("1" | "true"));
+    match header || query {
+        true => Durability::Synced,
+        false => state.config.durability,
+    }
+}
+
+// stored once, as the first item of a new file, so clients with a schema or column names
+// needn't repeat them in every event; they must send it with every request, as they can't
+// know when we rotate
+fn file_header(headers: &HeaderMap) -> Option<&[u8]> {
+    headers.get("x-batchy-file-header").map(|v| v.as_bytes())
+}
+
+// the overwhelmingly common responses, so we don't build and serialise a `Value` every time
+const STORED_BUFFERED: &str = r#"{"buffered":true,"durability":"buffered"}"#;
+const STORED_FLUSHED: &str = r#"{"buffered":true,"durability":"flushed"}"#;
+const STORED_SYNCED: &str = r#"{"buffered":true,"durability":"synced"}"#;
+
+fn stored(body: &'static str) -> Response {
+    ([(CONTENT_TYPE, "application/json")], body).into_response()
+}
+
+/// An event to store, before it's framed (see `read::TYPED_TS`).
+struct Event<'a> {
+    // in the live file's `precision`
+    ts: read::Timestamp,
+    // empty if there wasn't one, or it's not wanted
+    content_type: &'a [u8],
+    body: &'a [u8],
+}
+
+/// Write events to the live file (opening one if necessary), finishing the file if that fails.
+///
+/// They're all written under one hold of the lock, so they end up together, in order (unless
+/// the file is rotated part way through), and only the last is made `durability` durable, which
+/// covers the rest. The header is only written if an event is the first in its file, and
+/// ignored otherwise. If asked to `verify`, the last event is read back from the file, and must
+/// match.
+async fn append(
+    state: &Output,
+    events: &[Event<'_>],
+    header: Option<&[u8]>,
+    durability: Durability,
+    verify: bool,
+) -> Result<()> {
+    let result = append_inner(state, events, header, durability, verify).await;
+    if result.is_err() {
+        state.metrics.write_errors.fetch_add(1, Ordering::Relaxed);
+    }
+    match state.breaker.record(Instant::now(), result.is_ok()) {
+        Some(Transition::Opened) => state
+            .logger
+            .error((), "writes failing, circuit breaker opened"),
+        Some(Transition::Closed) => state.logger.info((), "circuit breaker closed"),
+        None => (),
+    }
+    result
+}
+
+async fn append_inner(
+    state: &Output,
+    events: &[Event<'_>],
+    header: Option<&[u8]>,
+    durability: Durability,
+    verify: bool,
+) -> Result<()> {
+    let mut opt = state.lock_any_writer().await;
+    // Compressing, writing, and (for some durabilities) syncing all happen with the lock held,
+    // so every other store queues behind them anyway; what we mustn't do is also hold up the
+    // runtime worker we're on, and everything else scheduled on it, e.g. probes and reads.
+    // The writer is borrowed from the guard, so this can't be `spawn_blocking`; the runtime
+    // is multi-threaded, so it can hand its other work to another thread while we're here.
+    tokio::task::block_in_place(|| {
+        write_events(state, &mut opt, events, header, durability, verify)
+    })
+}
 
-        match write(
-            &mut opt.as_mut().expect("just checked").inner,
-            &[&now.to_le_bytes(), &buf],
-        ) {
-            Ok(()) => Ok(json!({"buffered": true})),
+/// The blocking part of `append_inner`, with the lock already held.
+fn write_events(
+    state: &Output,
+    opt: &mut Option<Writer>,
+    events: &[Event<'_>],
+    header: Option<&[u8]>,
+    durability: Durability,
+    verify: bool,
+) -> Result<()> {
+    for (i, event) in events.iter().enumerate() {
+        if opt.is_none() {
+            opt.replace(new_file(&state.logger, &state.config)?);
+        }
+        let writer = opt.as_mut().expect("just checked");
+        let last = i + 1 == events.len();
+        let framed = match append_one(state, writer, event, header, last, durability) {
+            Ok(framed) => framed,
             Err(err) => {
-                if let Err(err) = finish(&state.logger, &mut opt) {
+                if let Err(err) = finish(&state.logger, opt) {
                     state
                         .logger
                         .warn(vars_dbg!(err), "unable to emergency finish");
                 }
-                Err(err)
+                return Err(err);
+            }
+        };
+
+        // we're still holding the lock, so ours is the last item in the file
+        if verify && last {
+            let mut found = None;
+            read::for_each_raw_item(&writer.path, |ts, body| {
+                found = Some((ts, body.to_vec()));
+                ControlFlow::Continue(())
+            })?;
+            let matched = found.is_some_and(|(ts, body)| {
+                framed.len() >= 8 && framed[..8] == ts.to_le_bytes() && framed[8..] == body
+            });
+            if !matched {
+                bail!("item not read back from {}", writer.name);
             }
         }
-    })
-    .await
+
+        // while we've still got the lock, so only one store can decide to do this
+        let max_events = state.config.max_events;
+        let max_file_bytes = state.config.max_file_bytes;
+        let reason = if max_events > 0 && writer.items >= max_events {
+            Some("event limit reached")
+        } else if max_file_bytes > 0 && writer.inner.get_mut().metadata()?.len() >= max_file_bytes {
+            Some("size limit reached")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            // what's already in the file is owed the same durability as what comes after
+            if !last {
+                make_durable(&mut writer.inner, durability)?;
+            }
+            rotate(state, opt, reason);
+        }
+    }
+    Ok(())
+}
+
+/// Frame and write one event (and the file's header and marker, if it's the first), returning
+/// the item as written. It's made `durability` durable only if it's the `last` of its batch.
+fn append_one(
+    state: &Output,
+    writer: &mut Writer,
+    event: &Event<'_>,
+    header: Option<&[u8]>,
+    last: bool,
+    durability: Durability,
+) -> Result<Vec<u8>> {
+    let inner = &mut writer.inner;
+    let before = match state.config.log_item_stats {
+        true => inner.get_mut().metadata().ok().map(|m| m.len()),
+        false => None,
+    };
+    let header_ts = read::HEADER_TS.to_le_bytes();
+    let header = header
+        .filter(|_| writer.items == 0)
+        .map(|header| [&header_ts[..], header]);
+    let typed_ts = read::TYPED_TS.to_le_bytes();
+    let marker = Some([&typed_ts[..]]).filter(|_| writer.typed && writer.items == 0);
+    let precision_ts = read::PRECISION_TS.to_le_bytes();
+    let precision = Some([&precision_ts[..], writer.precision.as_str().as_bytes()])
+        .filter(|_| writer.precision != read::Precision::Seconds && writer.items == 0);
+    let ts = event.ts.value.to_le_bytes();
+    // the content type goes between the timestamp and the payload; see `read::TYPED_TS`
+    let content_type_len = [u8::try_from(event.content_type.len())?];
+    let framed: &[&[u8]] = match writer.typed {
+        true => &[&ts, &content_type_len, event.content_type, event.body],
+        false => &[&ts, event.body],
+    };
+
+    if let Some(header) = &header {
+        write(inner, header)?;
+    }
+    if let Some(marker) = &marker {
+        write(inner, marker)?;
+    }
+    if let Some(precision) = &precision {
+        write(inner, precision)?;
+    }
+    write(inner, framed)?;
+    if last {
+        make_durable(inner, durability)?;
+    }
+    if let Some(before) = before {
+        if let Ok(after) = inner.get_mut().metadata() {
+            let item_bytes = framed.iter().map(|v| v.len()).sum::<usize>();
+            let file_bytes = after.len().saturating_sub(before);
+            state
+                .logger
+                .info(vars!(item_bytes, file_bytes), "item stats");
+        }
+    }
+
+    writer.items += 1;
+    let metrics = &state.metrics;
+    metrics.items_stored.fetch_add(1, Ordering::Relaxed);
+    metrics
+        .bytes_stored
+        .fetch_add(event.body.len() as u64, Ordering::Relaxed);
+    remember(state, event);
+    if let Some(header) = &header {
+        writer.mirror(&state.logger, header);
+    }
+    if let Some(marker) = &marker {
+        writer.mirror(&state.logger, marker);
+    }
+    if let Some(precision) = &precision {
+        writer.mirror(&state.logger, precision);
+    }
+    writer.mirror(&state.logger, framed);
+    Ok(framed.concat())
+}
+
+/// Replace the live file with a new one, e.g. because it's got too big. The item which
+/// triggered this has already been stored, so failures are logged, not returned; if there's no
+/// new file, the next store makes one.
+fn rotate(state: &Output, opt: &mut Option<Writer>, reason: &'static str) {
+    let previous = opt.as_ref().map(|writer| writer.name.clone());
+    if let Err(err) = finish(&state.logger, opt) {
+        state
+            .logger
+            .error(vars_dbg!(err), "unable to finish file for rotation");
+    }
+    match new_file(&state.logger, &state.config) {
+        Ok(next) => {
+            let next = opt.insert(next).name.clone();
+            state
+                .logger
+                .info(vars!(reason, previous, next), "rotated file");
+        }
+        Err(err) => {
+            state.logger.error(
+                vars_dbg!(reason, previous, err),
+                "unable to start file for rotation",
+            );
+        }
+    }
+}
+
+/// Keep an event in the recent buffer, pushing out the oldest if it's full.
+fn remember(state: &Output, event: &Event<'_>) {
+    let cap = state.config.recent_buffer;
+    if cap == 0 {
+        return;
+    }
+    let mut recent = state.recent.lock().expect("not poisoned");
+    if recent.len() == cap {
+        recent.pop_front();
+    }
+    recent.push_back((event.ts, Bytes::copy_from_slice(event.body)));
 }
 
 fn write<W: Write>(file: &mut CompressStream<W>, item: &[&[u8]]) -> Result<()> {
     file.write_item_vectored(item)?;
-    file.flush()?;
     Ok(())
 }
 
-fn path_for_now() -> String {
-    let time = OffsetDateTime::now_utc()
-        .format(&Rfc3339)
-        .expect("static formatter");
-    format!("{}.events.archiv", time)
+/// Get everything written so far to at least `durability`.
+fn make_durable(file: &mut CompressStream<fs::File>, durability: Durability) -> Result<()> {
+    if durability >= Durability::Flushed {
+        file.flush()?;
+    }
+    if durability >= Durability::Synced {
+        file.get_mut().sync_data()?;
+    }
+    Ok(())
 }
 
-fn new_file(logger: &Bunyarr) -> Result<Writer> {
-    let file_name = path_for_now();
-    let opts = CompressOptions::<'static>::default();
-    let inner = opts.stream_compress(fs::File::create(&file_name)?)?;
+fn new_file(logger: &Logger, config: &Config) -> Result<Writer> {
+    let sequence = config.sequence.as_ref().map(|seq| seq.next()).transpose()?;
+    let (file_name, file) = name::create(
+        &config.data_dir,
+        config.name_collision,
+        sequence,
+        OffsetDateTime::now_utc,
+    )?;
+    let preallocated = match config.prealloc_bytes {
+        0 => false,
+        bytes => match disk::preallocate(&file, bytes) {
+            Ok(()) => true,
+            Err(err) => {
+                logger.warn(
+                    vars_dbg!(file_name, err),
+                    "unable to preallocate, continuing",
+                );
+                false
+            }
+        },
+    };
+    let inner = compress_options(config).stream_compress(file)?;
     logger.info(vars!(file_name), "new event file created");
+
+    let mirror = match &config.mirror_dir {
+        Some(dir) => match new_mirror(config, dir, &file_name) {
+            Ok(mirror) => Some(mirror),
+            Err(err) => {
+                logger.error(
+                    vars_dbg!(file_name, err),
+                    "unable to create mirror, continuing without it for this file",
+                );
+                None
+            }
+        },
+        None => None,
+    };
+
     Ok(Writer {
         inner,
+        path: config.data_dir.join(&file_name),
         name: file_name,
+        created: OffsetDateTime::now_utc(),
+        items: 0,
+        mirror,
+        suspect: false,
+        preallocated,
+        typed: config.keep_content_type,
+        precision: config.timestamp_precision,
     })
 }
 
+fn new_mirror(
+    config: &Config,
+    dir: &Path,
+    file_name: &str,
+) -> Result<CompressStream<'static, fs::File>> {
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dir.join(file_name))?;
+    Ok(compress_options(config).stream_compress(file)?)
+}
+
+// what zstd uses when archiv asks for its default, i.e. level 0
+const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+fn compress_options(config: &Config) -> CompressOptions<'static> {
+    match config.compression_level {
+        Some(level) => CompressOptions::default().with_level(level),
+        None => CompressOptions::default(),
+    }
+}
+
+/// With `BATCHY_SHARDS`, there's a `live_file_names` list instead of `live_file_name`, and the
+/// sizes and counts are totals across them.
 async fn healthcheck(State(state): State<Arc<Output>>) -> (StatusCode, Json<Value>) {
-    match state.out.lock().await.as_ref() {
-        Some(_) => (StatusCode::OK, Json(json!({"ok": true}))),
-        None => (
+    let Some(names) = state.live_file_names() else {
+        return (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!({"msg": "writer unavailable"})),
+        );
+    };
+    // as of the last flush; null if one's gone missing, which `watch_live_file` fixes
+    let live_bytes = names
+        .iter()
+        .map(|name| fs::metadata(state.config.data_dir.join(&**name)).ok())
+        .map(|meta| meta.map(|meta| meta.len()))
+        .sum::<Option<u64>>();
+    let live_items = state
+        .shards
+        .iter()
+        .map(|shard| shard.live_items.load(Ordering::Relaxed))
+        .sum::<u64>();
+    let mut health = json!({
+        "ok": true,
+        "live_bytes": live_bytes,
+        "uptime_secs": state.started.elapsed().as_secs(),
+        "free_bytes": state.free_bytes.load(Ordering::Relaxed),
+        "breaker": state.breaker.state(Instant::now()),
+        "live_items": live_items,
+    });
+    let names = names.iter().map(|name| &**name).collect::<Vec<_>>();
+    match names.as_slice() {
+        [name] => health["live_file_name"] = json!(name),
+        names => health["live_file_names"] = json!(names),
+    }
+    (StatusCode::OK, Json(health))
+}
+
+async fn readyz(State(state): State<Arc<Output>>) -> Response {
+    let retry_after = state.config.retry_after;
+    if state.draining.load(Ordering::Relaxed) {
+        return unavailable(retry_after, json!({"ready": false, "msg": "draining"}));
+    }
+    if state.paused.load(Ordering::Relaxed) {
+        return unavailable(retry_after, json!({"ready": false, "msg": "paused"}));
+    }
+    match state.live_file_names() {
+        Some(_) => Json(json!({"ready": true})).into_response(),
+        None => unavailable(
+            retry_after,
+            json!({"ready": false, "msg": "writer unavailable"}),
         ),
     }
 }
 
+async fn not_found() -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({"error": "not found", "code": "NOT_FOUND"})),
+    )
+}
+
+fn build_router(state: Arc<Output>) -> Router {
+    // the raw files are already zstd (or gzip), so there's no point trying again
+    let compression = CompressionLayer::new().compress_when(
+        SizeAbove::new(state.config.compress_min_bytes)
+            .and(NotForContentType::GRPC)
+            .and(NotForContentType::IMAGES)
+            .and(NotForContentType::const_new("application/zstd"))
+            .and(NotForContentType::const_new("application/gzip")),
+    );
+
+    // the admin endpoints, and imports (including archives), aren't timed: they're rare, and
+    // cancelling one halfway would only mean somebody has to run it again
+    let retry_after = state.config.retry_after;
+    let store_timeout = |route| timeout(route, state.config.store_timeout, retry_after);
+    let read_timeout = |route| timeout(route, state.config.read_timeout, retry_after);
+    use axum::routing::{get, post};
+    // for load balancers and scrapers, which mightn't have the token, and the format, which
+    // says nothing about the data
+    let open = Router::new()
+        .route("/healthcheck", get(healthcheck))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics::metrics))
+        .route("/api/format", get(read::format));
+    let protected = Router::new()
+        .route("/store", store_timeout(post(store)))
+        .route(
+            "/store/multipart",
+            store_timeout(post(store_multipart).layer(DefaultBodyLimit::max(MAX_MULTIPART_BYTES))),
+        )
+        .route("/store/batch", store_timeout(post(store_batch)))
+        .route("/store/archive", post(store_archive))
+        .route("/api/raw", read_timeout(get(list_files)))
+        .route(
+            "/api/raw/:name",
+            read_timeout(get(fetch_raw)).put(import).delete(delete_file),
+        )
+        .route(
+            "/api/raw/:name/header",
+            read_timeout(get(read::fetch_header)),
+        )
+        .route("/api/raw/:name/items", read_timeout(get(read::items)))
+        .route("/api/raw/:name/ratio", read_timeout(get(read::ratio)))
+        .route("/api/raw/:name/size", read_timeout(get(read::size)))
+        .route("/api/raw/:name/recompress", post(recompress))
+        .route("/api/aggregate", read_timeout(get(read::aggregate)))
+        .route("/api/range", read_timeout(get(read::range)))
+        .route("/api/search/:name", read_timeout(get(read::search)))
+        .route("/api/events", read_timeout(get(read::between)))
+        .route("/api/events/:name", read_timeout(get(read::events)))
+        .route("/api/recent", read_timeout(get(read::recent)))
+        .route("/api/read/:name", read_timeout(get(read::read_file)))
+        .route("/api/tail", read_timeout(get(read::tail)))
+        .route("/api/cycle", post(cycle))
+        .route("/api/checkpoint", post(checkpoint))
+        .route("/api/rotation", get(rotation))
+        .route("/api/status", get(status))
+        .route("/api/drain", post(drain))
+        .route("/api/pause", post(pause))
+        .route("/api/resume", post(resume));
+    let protected = match state.config.token {
+        Some(_) => protected.route_layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            auth::require_token,
+        )),
+        None => protected,
+    };
+    protected
+        .merge(open)
+        .fallback(not_found)
+        .layer(compression)
+        .with_state(state)
+}
+
+/// Answer with a 503 if the route hasn't produced a response in time. Only the response's
+/// headers have to arrive in time; a streamed body can carry on for as long as it likes.
+fn timeout(
+    route: MethodRouter<Arc<Output>>,
+    limit: Option<Duration>,
+    retry_after: u64,
+) -> MethodRouter<Arc<Output>> {
+    let Some(limit) = limit else {
+        return route;
+    };
+    route.layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(move |_: BoxError| async move {
+                unavailable(retry_after, json!({ "error": "timed out" }))
+            }))
+            .timeout(limit),
+    )
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let logger = Bunyarr::with_name("batchy");
+    let logger = Logger::with_name("batchy");
+    let config = Config::from_env()?;
+    // so it's clear whether `BATCHY_COMPRESSION_LEVEL` was picked up
+    let compression_level = config
+        .compression_level
+        .unwrap_or(DEFAULT_COMPRESSION_LEVEL);
+    logger.info(vars!(compression_level), "compressing new files");
+    let mut resumed = match config.resume_within {
+        Some(within) => resume_leftovers(&config, &logger, within)?,
+        None => Vec::new(),
+    };
+    if config.finish_on_startup {
+        let paths = resumed.iter().map(|w| w.path.clone()).collect::<Vec<_>>();
+        finish_leftovers(&config.data_dir, &logger, &paths)?;
+    }
 
-    let rc = Arc::new(sync::Mutex::new(Some(new_file(&logger)?)));
+    let shards = (0..config.shards)
+        .map(|_| match resumed.pop() {
+            Some(writer) => Ok(Shard::new(writer)),
+            None => Ok(Shard::new(new_file(&logger, &config)?)),
+        })
+        .collect::<Result<Vec<_>>>()?;
     let state = Output {
-        out: Arc::clone(&rc),
-        logger: Bunyarr::with_name("batchy-handler"),
+        shards,
+        next_shard: AtomicUsize::new(0),
+        logger: Logger::with_name("batchy-handler"),
+        scheduler_logger: Logger::with_threshold("batchy-scheduler", config.scheduler_log_level),
+        draining: AtomicBool::new(false),
+        paused: AtomicBool::new(false),
+        free_bytes: AtomicU64::new(disk::free_bytes(&config.data_dir)?),
+        next_time_cycle: AtomicI64::new(0),
+        ratio_cache: Default::default(),
+        recent: Default::default(),
+        catalog_stale: Default::default(),
+        started: Instant::now(),
+        metrics: Default::default(),
+        breaker: Breaker::new(
+            config.breaker_failures,
+            config.breaker_window,
+            config.breaker_cooldown,
+        ),
+        config,
     };
 
-    use axum::routing::{get, post};
     let state = Arc::new(state);
-    let app = Router::new()
-        .route("/store", post(store))
-        .route("/healthcheck", get(healthcheck))
-        .route("/api/raw", get(list_files))
-        .route("/api/raw/:name", get(fetch_raw))
-        .route("/api/cycle", post(cycle))
-        .with_state(Arc::clone(&state));
+    let app = build_router(Arc::clone(&state));
 
-    tokio::spawn(time_based_cycle(state));
+    let mut tasks = shutdown::Tasks::new();
+    tasks.spawn(
+        "time_based_cycle",
+        time_based_cycle(Arc::clone(&state), state.config.cycle_interval),
+    );
+    tasks.spawn("watch_live_file", watch_live_file(Arc::clone(&state)));
+    tasks.spawn(
+        "watch_free_space",
+        disk::watch_free_space(Arc::clone(&state)),
+    );
+    if state.config.catalog {
+        tasks.spawn("catalog", catalog::maintain(Arc::clone(&state)));
+    }
+    if let Some(keep) = state.config.retention {
+        tasks.spawn("retention", retention::expire(Arc::clone(&state), keep));
+    }
+    let uds_path = state.config.uds_path.clone();
+    let tcp = Some(state.config.listen).filter(|_| !state.config.uds_only);
 
-    let port = 3000;
-    logger.info(vars!(port), "server starting");
-    axum::Server::bind(&(Ipv6Addr::UNSPECIFIED, port).into())
-        .serve(app.into_make_service())
-        .with_graceful_shutdown(shutdown::shutdown_signal())
-        .await?;
+    listen::serve(&logger, app, tcp, uds_path.as_deref()).await?;
+    logger.info((), "shutdown: stopped serving requests");
+    // so nothing can start a new file after we've finished this one
+    tasks.stop(&logger).await;
 
-    let mut guard = rc.lock().await;
-    finish(&logger, &mut guard)?;
+    for shard in &state.shards {
+        let mut guard = shard.out.lock().await;
+        finish(&logger, &mut guard)?;
+    }
 
     logger.info((), "shutdown success");
+    log::flush();
     Ok(())
 }
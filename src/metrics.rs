@@ -0,0 +1,111 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::{IntoResponse, Response};
+
+use crate::Output;
+
+/// Counted as things happen, without the writer lock; everything else in `/metrics` is read
+/// from elsewhere in `Output` when it's scraped.
+#[derive(Default)]
+pub struct Counters {
+    // events written, however they arrived
+    pub items_stored: AtomicU64,
+    // their payloads, after any `BATCHY_TRANSFORM`, not counting timestamps or framing
+    pub bytes_stored: AtomicU64,
+    // times `append` failed, each of which could have been many events
+    pub write_errors: AtomicU64,
+    // live files replaced, for whatever reason, maintained by `WriterGuard`
+    pub cycles: AtomicU64,
+}
+
+/// The Prometheus text exposition format, for scraping.
+pub async fn metrics(State(state): State<Arc<Output>>) -> Response {
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let counters = &state.metrics;
+    let shards = &state.shards;
+    let live_bytes = shards
+        .iter()
+        .filter_map(|shard| shard.live_file_name())
+        .filter_map(|name| fs::metadata(state.config.data_dir.join(&*name)).ok())
+        .map(|meta| meta.len())
+        .sum();
+    let live_items = shards.iter().map(|shard| load(&shard.live_items)).sum();
+    let breaker = state.breaker.state(Instant::now());
+    let recent = state.recent.lock().expect("not poisoned").len();
+
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, values: &[(&str, u64)]| {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for (labels, value) in values {
+            let _ = writeln!(out, "{name}{labels} {value}");
+        }
+    };
+    metric(
+        "batchy_items_stored_total",
+        "counter",
+        "Events written to the live file.",
+        &[("", load(&counters.items_stored))],
+    );
+    metric(
+        "batchy_stored_bytes_total",
+        "counter",
+        "Payload bytes written, not counting framing.",
+        &[("", load(&counters.bytes_stored))],
+    );
+    metric(
+        "batchy_write_errors_total",
+        "counter",
+        "Attempts to write events which failed.",
+        &[("", load(&counters.write_errors))],
+    );
+    metric(
+        "batchy_cycles_total",
+        "counter",
+        "Times the live file was replaced by a new one.",
+        &[("", load(&counters.cycles))],
+    );
+    metric(
+        "batchy_live_file_bytes",
+        "gauge",
+        "Size of the live files on disk, as of their last flushes.",
+        &[("", live_bytes)],
+    );
+    metric(
+        "batchy_live_file_items",
+        "gauge",
+        "Events in the live files.",
+        &[("", live_items)],
+    );
+    metric(
+        "batchy_free_bytes",
+        "gauge",
+        "Free space in the data dir, as of the last check.",
+        &[("", load(&state.free_bytes))],
+    );
+    metric(
+        "batchy_recent_buffer_items",
+        "gauge",
+        "Events held for /api/tail.",
+        &[("", recent as u64)],
+    );
+    metric(
+        "batchy_breaker_state",
+        "gauge",
+        "Whether the write circuit breaker is in each state.",
+        &[
+            ("closed", r#"{state="closed"}"#),
+            ("open", r#"{state="open"}"#),
+            ("half-open", r#"{state="half-open"}"#),
+        ]
+        .map(|(candidate, labels)| (labels, u64::from(breaker == candidate))),
+    );
+
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], out).into_response()
+}
@@ -0,0 +1,369 @@
+use std::io::Write as _;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::{fs, io};
+
+use anyhow::{bail, Context as _, Result};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// Event files are named `<name>.events.archiv`, and the api refers to them by just `<name>`.
+pub const SUFFIX: &str = ".events.archiv";
+
+/// The components of a file's `<name>`.
+///
+/// The name is the RFC3339 instant the file was created, as we write it in UTC (e.g.
+/// `2023-06-17T10:11:12.123456789Z`), then `@<seq>` if `BATCHY_SEQUENCE` was on, then `~<n>`
+/// if that name was already taken, under `Collision::Suffix`. Any RFC3339 offset is accepted,
+/// so files renamed or restored by hand still work, but nothing else is: there's no lenient
+/// fallback.
+#[derive(Debug, PartialEq, Eq)]
+pub struct FileName {
+    pub instant: OffsetDateTime,
+    pub sequence: Option<u64>,
+    pub disambiguator: Option<u32>,
+}
+
+/// What to do when the name we want for a new file is already taken, which can happen if the
+/// clock goes backwards, or a file has been restored or imported with a future date.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Collision {
+    /// refuse to create the file
+    Error,
+    /// wait for the clock to move on, and try a fresh name
+    Skip,
+    /// add a `~<n>` to the name
+    Suffix,
+}
+
+impl FromStr for Collision {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "error" => Collision::Error,
+            "skip" => Collision::Skip,
+            "suffix" => Collision::Suffix,
+            _ => return Err("expected one of: error, skip, suffix"),
+        })
+    }
+}
+
+/// The `<name>` part of an on-disk file name, or None if it isn't one of ours at all.
+pub fn split_suffix(file_name: &str) -> Option<&str> {
+    file_name.strip_suffix(SUFFIX)
+}
+
+/// None if this is malformed; callers looking at the filesystem should log rather than skip.
+pub fn parse_name(name: &str) -> Option<FileName> {
+    let (name, disambiguator) = match name.rsplit_once('~') {
+        Some((name, n)) => (name, Some(parse_counter(n)?)),
+        None => (name, None),
+    };
+    let (instant, sequence) = match name.rsplit_once('@') {
+        Some((instant, n)) => (instant, Some(parse_counter(n)?)),
+        None => (name, None),
+    };
+    let instant = OffsetDateTime::parse(instant, &Rfc3339).ok()?;
+    Some(FileName {
+        instant,
+        sequence,
+        disambiguator,
+    })
+}
+
+// only in the one form we write, so names are unambiguous
+fn parse_counter<T: FromStr>(n: &str) -> Option<T> {
+    if n.starts_with(['0', '+']) {
+        return None;
+    }
+    n.parse().ok()
+}
+
+/// The on-disk name for an api name.
+pub fn file_name(name: &str) -> String {
+    format!("{}{}", name, SUFFIX)
+}
+
+/// Where an api name's file is, in `dir`, or None if it isn't a valid name. A valid name can't
+/// have a `/` or `..` in it, but we check anyway, as a name from a client which got out of
+/// the directory would be a disaster.
+pub fn path(dir: &Path, name: &str) -> Option<PathBuf> {
+    parse_name(name)?;
+    let file_name = file_name(name);
+    let mut components = Path::new(&file_name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Some(dir.join(file_name)),
+        _ => None,
+    }
+}
+
+pub fn name_for(instant: OffsetDateTime) -> String {
+    instant.format(&Rfc3339).expect("static formatter")
+}
+
+/// Create (never truncate) a new file in `dir`, named for the time, and the sequence number if
+/// there is one, returning its file name.
+pub fn create(
+    dir: &Path,
+    policy: Collision,
+    sequence: Option<u64>,
+    mut now: impl FnMut() -> OffsetDateTime,
+) -> Result<(String, fs::File)> {
+    let mut name_now = || match sequence {
+        Some(seq) => format!("{}@{seq}", name_for(now())),
+        None => name_for(now()),
+    };
+    let mut name = name_now();
+    let mut attempt = 0u32;
+    loop {
+        let candidate = match (policy, attempt) {
+            (Collision::Suffix, n) if n > 0 => file_name(&format!("{name}~{n}")),
+            _ => file_name(&name),
+        };
+        match fs::File::options()
+            .write(true)
+            .create_new(true)
+            .open(dir.join(&candidate))
+        {
+            Ok(file) => return Ok((candidate, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(err) => return Err(err.into()),
+        }
+
+        attempt += 1;
+        match policy {
+            Collision::Error => bail!("refusing to overwrite existing file: {candidate}"),
+            Collision::Skip if attempt < 1000 => {
+                std::thread::sleep(std::time::Duration::from_micros(10));
+                name = name_now();
+            }
+            Collision::Suffix if attempt < 1000 => (),
+            _ => bail!("unable to find a free name near {candidate}"),
+        }
+    }
+}
+
+/// A counter for files' `@<seq>`, so they have a strict order even if the clock doesn't.
+///
+/// The last number handed out is kept in `batchy.seq`, which is rewritten (atomically, and
+/// synced) before each number is used. In case that's lost, we also never go below anything
+/// already in a file name.
+pub struct Sequence {
+    dir: PathBuf,
+    last: Mutex<u64>,
+}
+
+const SEQUENCE_FILE: &str = "batchy.seq";
+
+impl Sequence {
+    pub fn load(dir: &Path) -> Result<Sequence> {
+        let mut last = match fs::read_to_string(dir.join(SEQUENCE_FILE)) {
+            Ok(val) => val
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid {SEQUENCE_FILE}: {val:?}"))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err.into()),
+        };
+        for entry in fs::read_dir(dir)? {
+            let parsed = entry?
+                .file_name()
+                .to_str()
+                .and_then(split_suffix)
+                .and_then(parse_name);
+            if let Some(seq) = parsed.and_then(|f| f.sequence) {
+                last = last.max(seq);
+            }
+        }
+        Ok(Sequence {
+            dir: dir.to_path_buf(),
+            last: Mutex::new(last),
+        })
+    }
+
+    /// The next number, which is on disk before it's returned, so it's never reused.
+    pub fn next(&self) -> Result<u64> {
+        let mut last = self.last.lock().expect("not poisoned");
+        let next = *last + 1;
+        let temp = self.dir.join(format!("{SEQUENCE_FILE}.tmp"));
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(next.to_string().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp, self.dir.join(SEQUENCE_FILE))?;
+        *last = next;
+        Ok(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::*;
+
+    #[test]
+    fn ours() {
+        let name = split_suffix("2023-06-17T10:11:12.123456789Z.events.archiv").unwrap();
+        assert_eq!(name, "2023-06-17T10:11:12.123456789Z");
+        assert_eq!(
+            parse_name(name),
+            Some(FileName {
+                instant: datetime!(2023-06-17 10:11:12.123456789 UTC),
+                sequence: None,
+                disambiguator: None,
+            })
+        );
+    }
+
+    #[test]
+    fn offsets() {
+        assert_eq!(
+            parse_name("2023-06-17T11:11:12+01:00").map(|f| f.instant),
+            Some(datetime!(2023-06-17 10:11:12 UTC))
+        );
+    }
+
+    #[test]
+    fn not_ours() {
+        assert_eq!(split_suffix("2023-06-17T10:11:12Z.events.zstd"), None);
+        assert_eq!(split_suffix("2023-06-17T10:11:12Z.events.archiv.tmp"), None);
+        assert_eq!(split_suffix("notes.txt"), None);
+    }
+
+    #[test]
+    fn malformed() {
+        for name in [
+            "",
+            "yesterday",
+            "2023-06-17",
+            "2023-06-17T10:11:12",
+            "2023-06-17T25:11:12Z",
+            "2023-13-17T10:11:12Z",
+            "../2023-06-17T10:11:12Z",
+            "2023-06-17T10:11:12Z~",
+            "2023-06-17T10:11:12Z~0",
+            "2023-06-17T10:11:12Z~01",
+            "2023-06-17T10:11:12Z~+1",
+            "2023-06-17T10:11:12Z~x",
+            "2023-06-17T10:11:12Z@",
+            "2023-06-17T10:11:12Z@01",
+            "2023-06-17T10:11:12Z~1@1",
+        ] {
+            assert_eq!(parse_name(name), None, "{name:?}");
+        }
+    }
+
+    #[test]
+    fn paths() {
+        let dir = Path::new("/data");
+        assert_eq!(
+            path(dir, "2023-06-17T10:11:12Z"),
+            Some(PathBuf::from("/data/2023-06-17T10:11:12Z.events.archiv"))
+        );
+        for name in ["../2023-06-17T10:11:12Z", "x/2023-06-17T10:11:12Z", ".."] {
+            assert_eq!(path(dir, name), None, "{name:?}");
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let instant = datetime!(2023-06-17 10:11:12.5 UTC);
+        let file_name = file_name(&name_for(instant));
+        assert_eq!(file_name, "2023-06-17T10:11:12.5Z.events.archiv");
+        let parsed = parse_name(split_suffix(&file_name).unwrap()).unwrap();
+        assert_eq!(parsed.instant, instant);
+    }
+
+    #[test]
+    fn disambiguated() {
+        assert_eq!(
+            parse_name("2023-06-17T10:11:12Z~12"),
+            Some(FileName {
+                instant: datetime!(2023-06-17 10:11:12 UTC),
+                sequence: None,
+                disambiguator: Some(12),
+            })
+        );
+    }
+
+    #[test]
+    fn sequenced() {
+        assert_eq!(
+            parse_name("2023-06-17T10:11:12Z@7~2"),
+            Some(FileName {
+                instant: datetime!(2023-06-17 10:11:12 UTC),
+                sequence: Some(7),
+                disambiguator: Some(2),
+            })
+        );
+    }
+
+    #[test]
+    fn sequence_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let seq = Sequence::load(dir.path()).unwrap();
+        assert_eq!(seq.next().unwrap(), 1);
+        assert_eq!(seq.next().unwrap(), 2);
+        assert_eq!(Sequence::load(dir.path()).unwrap().next().unwrap(), 3);
+
+        // losing the state file doesn't go backwards past what's in use
+        fs::remove_file(dir.path().join(SEQUENCE_FILE)).unwrap();
+        fs::write(dir.path().join(file_name("2023-06-17T10:11:12Z@9")), b"").unwrap();
+        assert_eq!(Sequence::load(dir.path()).unwrap().next().unwrap(), 10);
+
+        let (created, _) = create(dir.path(), Collision::Error, Some(10), || TAKEN).unwrap();
+        assert_eq!(created, "2023-06-17T10:11:12Z@10.events.archiv");
+    }
+
+    const TAKEN: OffsetDateTime = datetime!(2023-06-17 10:11:12 UTC);
+
+    fn occupied() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(file_name(&name_for(TAKEN))), b"precious").unwrap();
+        dir
+    }
+
+    fn precious_survived(dir: &tempfile::TempDir) {
+        let existing = fs::read(dir.path().join(file_name(&name_for(TAKEN)))).unwrap();
+        assert_eq!(existing, b"precious");
+    }
+
+    #[test]
+    fn collision_error() {
+        let dir = occupied();
+        assert!(create(dir.path(), Collision::Error, None, || TAKEN).is_err());
+        precious_survived(&dir);
+    }
+
+    #[test]
+    fn collision_skip() {
+        let dir = occupied();
+        let mut clock = [TAKEN, TAKEN, datetime!(2023-06-17 10:11:13 UTC)].into_iter();
+        let (created, _) =
+            create(dir.path(), Collision::Skip, None, || clock.next().unwrap()).unwrap();
+        assert_eq!(created, "2023-06-17T10:11:13Z.events.archiv");
+        precious_survived(&dir);
+    }
+
+    #[test]
+    fn collision_suffix() {
+        let dir = occupied();
+        let (first, _) = create(dir.path(), Collision::Suffix, None, || TAKEN).unwrap();
+        assert_eq!(first, "2023-06-17T10:11:12Z~1.events.archiv");
+        let (second, _) = create(dir.path(), Collision::Suffix, None, || TAKEN).unwrap();
+        assert_eq!(second, "2023-06-17T10:11:12Z~2.events.archiv");
+        precious_survived(&dir);
+    }
+
+    #[test]
+    fn no_collision() {
+        let dir = tempfile::tempdir().unwrap();
+        for policy in [Collision::Error, Collision::Skip, Collision::Suffix] {
+            let instant = TAKEN + time::Duration::seconds(policy as i64);
+            let (created, _) = create(dir.path(), policy, None, || instant).unwrap();
+            assert_eq!(created, file_name(&name_for(instant)));
+        }
+    }
+}
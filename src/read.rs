@@ -0,0 +1,1404 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{Read, Write as _};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::{fs, io};
+
+use anyhow::{bail, Result};
+use axum::body::{self, Body, Bytes};
+use axum::extract::{Query, State};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use bunyarrs::{vars, vars_dbg};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Deserialize;
+use serde_json::json;
+use serde_json::Value;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::admin::{event_files, files_overlapping, parse_date, EventFile, NameParam};
+use crate::{bad_request, internal_error, json_error, name, okay_or_500, Output};
+
+// how many files `/api/recent` will stitch together
+const MAX_RECENT_FILES: usize = 100;
+
+// for the endpoints which decode every item, as a crude bound on how long they can take
+const MAX_SCANNED: u64 = 1_000_000;
+
+// sparse, so this only bites if someone asks for minutes over a very busy year
+const MAX_BUCKETS: usize = 10_000;
+
+/// The timestamp of an item which isn't an event, but the file's header, as sent by the client
+/// with the file's first event. If it's present, it's the first item.
+pub const HEADER_TS: i64 = i64::MIN;
+
+/// The timestamp of an item which marks the file as written with `BATCHY_KEEP_CONTENT_TYPE`,
+/// so every event after it is `timestamp(8) ++ len(1) ++ content_type(len) ++ payload`, instead
+/// of just `timestamp(8) ++ payload`. If it's present, it's right after the header, if there is
+/// one, and it has no payload of its own. An empty content type means there wasn't one.
+pub const TYPED_TS: i64 = i64::MIN + 1;
+
+/// The timestamp of an item which says the file's events' timestamps aren't whole seconds, but
+/// the `Precision` named in its payload, e.g. `millis`. If it's present, it's next to the
+/// `TYPED_TS` marker (if there is one), before the first event.
+pub const PRECISION_TS: i64 = i64::MIN + 2;
+
+/// Bumped whenever anything `format` describes changes.
+const FORMAT_VERSION: u32 = 3;
+
+/// How event files are laid out, for consumers which decode them themselves, and want to
+/// check they can before they try. Describes nothing which isn't public anyway.
+pub async fn format() -> Json<Value> {
+    Json(json!({
+        "version": FORMAT_VERSION,
+        "codec": "archiv",
+        "compression": "zstd",
+        "suffix": name::SUFFIX,
+        "item": {
+            "prefix_bytes": 8,
+            "fields": [
+                {
+                    "name": "timestamp",
+                    "bytes": 8,
+                    "encoding": "i64-le",
+                    "unit": "unix-seconds, unless the file has a precision marker",
+                },
+                { "name": "payload", "bytes": null, "encoding": "opaque" },
+            ],
+            "header_timestamp": HEADER_TS,
+            "content_type": {
+                "marker_timestamp": TYPED_TS,
+                "prefix": "in files with the marker, between the timestamp and the payload, \
+                    a u8 length, then that many bytes of Content-Type; empty if there was none",
+            },
+            "precision": {
+                "marker_timestamp": PRECISION_TS,
+                "payload": "the timestamps' unit from then on: seconds, millis, or nanos, \
+                    since the unix epoch",
+            },
+        },
+        "ordering": "items are in the order they were stored, and timestamps never decrease \
+            within a file unless the clock does",
+    }))
+}
+
+/// How finely a file's timestamps are recorded, `BATCHY_TIMESTAMP_PRECISION` for new files.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Precision {
+    Seconds,
+    Millis,
+    Nanos,
+}
+
+impl Precision {
+    fn per_second(self) -> i64 {
+        match self {
+            Precision::Seconds => 1,
+            Precision::Millis => 1_000,
+            Precision::Nanos => 1_000_000_000,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Precision::Seconds => "seconds",
+            Precision::Millis => "millis",
+            Precision::Nanos => "nanos",
+        }
+    }
+
+    /// The current time, in these units.
+    pub fn now(self) -> Timestamp {
+        let nanos = OffsetDateTime::now_utc().unix_timestamp_nanos();
+        let value = nanos.div_euclid(i128::from(1_000_000_000 / self.per_second()));
+        Timestamp {
+            // nanoseconds run out in 2262
+            value: value as i64,
+            precision: self,
+        }
+    }
+}
+
+impl FromStr for Precision {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "seconds" => Precision::Seconds,
+            "millis" => Precision::Millis,
+            "nanos" => Precision::Nanos,
+            _ => return Err("expected one of: seconds, millis, nanos"),
+        })
+    }
+}
+
+/// An event's time, as it's recorded in its file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Timestamp {
+    pub value: i64,
+    pub precision: Precision,
+}
+
+impl Timestamp {
+    /// Whole unix seconds, rounded down, as most of the api deals in.
+    pub fn secs(self) -> i64 {
+        self.value.div_euclid(self.precision.per_second())
+    }
+
+    pub fn nanos(self) -> i64 {
+        self.value
+            .saturating_mul(1_000_000_000 / self.precision.per_second())
+    }
+
+    /// `nanos`, if there's more to it than `secs`, as the read endpoints' `ts_nanos`.
+    pub fn finer_nanos(self) -> Option<i64> {
+        (self.precision != Precision::Seconds).then(|| self.nanos())
+    }
+}
+
+/// Walk the events in an event file, handing over the timestamp (in the file's `Precision`;
+/// most of the api wants `Timestamp::secs`) and the payload.
+///
+/// Files which haven't been finished (the live file, or one left by a crash) have no footer,
+/// and may stop mid-frame; that's treated as the end of the file, not an error.
+pub fn for_each_item(
+    path: &Path,
+    mut f: impl FnMut(Timestamp, &[u8]) -> ControlFlow<()>,
+) -> Result<()> {
+    walk_events(path, |ts, _, body| f(ts, body))?;
+    Ok(())
+}
+
+/// As `walk`, but only the events, with their full timestamps, and their content types, if
+/// the file has them (see `TYPED_TS`).
+pub fn walk_events(
+    path: &Path,
+    f: impl FnMut(Timestamp, Option<&[u8]>, &[u8]) -> ControlFlow<()>,
+) -> Result<bool> {
+    walk_events_with(
+        path,
+        |len| bail!("item too short to contain a timestamp: {len}"),
+        f,
+    )
+}
+
+/// As `walk_events`, with `walk_with`'s handling of short items.
+fn walk_events_with(
+    path: &Path,
+    malformed: impl FnMut(usize) -> Result<()>,
+    mut f: impl FnMut(Timestamp, Option<&[u8]>, &[u8]) -> ControlFlow<()>,
+) -> Result<bool> {
+    let mut typed = false;
+    let mut precision = Precision::Seconds;
+    let mut failure = None;
+    let finished = walk_with(path, malformed, |ts, body| match ts {
+        HEADER_TS => ControlFlow::Continue(()),
+        TYPED_TS => {
+            typed = true;
+            ControlFlow::Continue(())
+        }
+        PRECISION_TS => match std::str::from_utf8(body).ok().and_then(|v| v.parse().ok()) {
+            Some(marked) => {
+                precision = marked;
+                ControlFlow::Continue(())
+            }
+            None => {
+                failure = Some("unknown timestamp precision");
+                ControlFlow::Break(())
+            }
+        },
+        value => {
+            let ts = Timestamp { value, precision };
+            match typed {
+                true => match split_content_type(body) {
+                    Some((content_type, body)) => f(ts, Some(content_type), body),
+                    None => {
+                        failure = Some("item too short to contain its content type");
+                        ControlFlow::Break(())
+                    }
+                },
+                false => f(ts, None, body),
+            }
+        }
+    })?;
+    if let Some(failure) = failure {
+        bail!("{failure}");
+    }
+    Ok(finished)
+}
+
+fn split_content_type(body: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (&len, rest) = body.split_first()?;
+    let len = usize::from(len);
+    (rest.len() >= len).then(|| rest.split_at(len))
+}
+
+/// As `walk`, including the header (and any markers), and the events as they're
+/// framed on disk, for things which copy files.
+pub fn for_each_raw_item(path: &Path, f: impl FnMut(i64, &[u8]) -> ControlFlow<()>) -> Result<()> {
+    walk(path, f)?;
+    Ok(())
+}
+
+/// The total length of the items in the file, i.e. what it would be uncompressed, less framing.
+pub fn uncompressed_size(path: &Path) -> Result<u64> {
+    Ok(item_stats(path)?.1)
+}
+
+/// How many events the file holds (not counting the header or markers), and its
+/// `uncompressed_size`, from one pass over it.
+pub fn item_stats(path: &Path) -> Result<(u64, u64)> {
+    let mut events = 0u64;
+    let mut total = 0u64;
+    for_each_raw_item(path, |ts, body| {
+        if ts > PRECISION_TS {
+            events += 1;
+        }
+        total += 8 + body.len() as u64;
+        ControlFlow::Continue(())
+    })?;
+    Ok((events, total))
+}
+
+/// The file's header, if it has one.
+pub fn header(path: &Path) -> Result<Option<Vec<u8>>> {
+    let mut header = None;
+    walk(path, |ts, body| {
+        if ts == HEADER_TS {
+            header = Some(body.to_vec());
+        }
+        ControlFlow::Break(())
+    })?;
+    Ok(header)
+}
+
+/// Whether the file was finished (i.e. has a footer), which means reading all of it.
+pub fn is_finished(path: &Path) -> Result<bool> {
+    walk(path, |_, _| ControlFlow::Continue(()))
+}
+
+/// As `for_each_raw_item`, but also whether the file was finished, as `is_finished`, unless
+/// `f` stopped early.
+pub fn walk(path: &Path, f: impl FnMut(i64, &[u8]) -> ControlFlow<()>) -> Result<bool> {
+    walk_with(
+        path,
+        |len| bail!("item too short to contain a timestamp: {len}"),
+        f,
+    )
+}
+
+/// As `walk`, but items too short to have a timestamp are handed to `malformed` (with their
+/// length), and skipped, unless it fails.
+fn walk_with(
+    path: &Path,
+    mut malformed: impl FnMut(usize) -> Result<()>,
+    mut f: impl FnMut(i64, &[u8]) -> ControlFlow<()>,
+) -> Result<bool> {
+    let file = fs::File::open(path)?;
+    // nothing has been flushed yet, not even the header (which `stream` can't cope with)
+    if file.metadata()?.len() == 0 {
+        return Ok(false);
+    }
+    let opts = archiv::ExpandOptions::default();
+    let mut archiv = match opts.stream(io::BufReader::new(file)) {
+        Ok(archiv) => archiv,
+        // only part of the header was flushed, e.g. we crashed just after creating the file
+        Err(err) if is_eof(&err) => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+    let mut buf = Vec::new();
+    loop {
+        let mut item = match archiv.next_item() {
+            Ok(Some(item)) => item,
+            Ok(None) => return Ok(true),
+            Err(err) if is_eof(&err) => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+        buf.clear();
+        match item.read_to_end(&mut buf) {
+            Ok(_) => (),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(err) => return Err(err.into()),
+        }
+        if buf.len() < 8 {
+            malformed(buf.len())?;
+            continue;
+        }
+        let (ts, body) = buf.split_at(8);
+        let ts = i64::from_le_bytes(ts.try_into().expect("split at 8"));
+        if f(ts, body).is_break() {
+            // we don't know, but nobody's asking
+            return Ok(false);
+        }
+    }
+}
+
+fn is_eof(err: &archiv::Error) -> bool {
+    matches!(err, archiv::Error::Io { source } if source.kind() == io::ErrorKind::UnexpectedEof)
+}
+
+#[derive(Deserialize)]
+pub struct AggregateQuery {
+    from: Option<String>,
+    to: Option<String>,
+    by: Option<String>,
+}
+
+#[derive(Default)]
+struct Bucket {
+    count: u64,
+    bytes: u64,
+}
+
+/// Event counts and payload byte totals, bucketed by the minute, hour, or day.
+pub async fn aggregate(
+    State(state): State<Arc<Output>>,
+    Query(query): Query<AggregateQuery>,
+) -> (StatusCode, Json<Value>) {
+    let from = match query.from.as_deref().map(parse_date) {
+        Some(None) => return bad_request("invalid from"),
+        from => from.flatten(),
+    };
+    let to = match query.to.as_deref().map(parse_date) {
+        Some(None) => return bad_request("invalid to"),
+        to => to.flatten(),
+    };
+    let by = query.by.unwrap_or_else(|| "hour".to_string());
+    let width: i64 = match by.as_str() {
+        "minute" => 60,
+        "hour" => 60 * 60,
+        "day" => 24 * 60 * 60,
+        _ => return bad_request("by must be one of: minute, hour, day"),
+    };
+
+    okay_or_500(&state.logger, || async {
+        let output = Arc::clone(&state);
+        // every event in the range is decoded, which can take a while
+        let (buckets, truncated) =
+            tokio::task::spawn_blocking(move || buckets(&output, from, to, width)).await??;
+
+        let mut rows = Vec::with_capacity(buckets.len());
+        for (start, bucket) in buckets {
+            let start = OffsetDateTime::from_unix_timestamp(start)?.format(&Rfc3339)?;
+            rows.push(json!({ "start": start, "count": bucket.count, "bytes": bucket.bytes }));
+        }
+        Ok(json!({ "by": by, "buckets": rows, "truncated": truncated }))
+    })
+    .await
+}
+
+/// The events in the range, in buckets `width` seconds wide, and whether there were too many
+/// buckets to keep them all.
+fn buckets(
+    state: &Output,
+    from: Option<OffsetDateTime>,
+    to: Option<OffsetDateTime>,
+    width: i64,
+) -> Result<(BTreeMap<i64, Bucket>, bool)> {
+    let from_ts = from.map(|v| v.unix_timestamp());
+    let to_ts = to.map(|v| v.unix_timestamp());
+    let mut buckets = BTreeMap::<i64, Bucket>::new();
+    let mut truncated = false;
+    for file in files_overlapping(
+        &state.config.data_dir,
+        &state.logger,
+        state.config.shards,
+        from,
+        to,
+    )? {
+        for_each_item(&file.path, |ts, body| {
+            let ts = ts.secs();
+            if from_ts.is_some_and(|from| ts < from) || to_ts.is_some_and(|to| ts > to) {
+                return ControlFlow::Continue(());
+            }
+            let start = ts - ts.rem_euclid(width);
+            if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&start) {
+                truncated = true;
+                return ControlFlow::Continue(());
+            }
+            let bucket = buckets.entry(start).or_default();
+            bucket.count += 1;
+            bucket.bytes += body.len() as u64;
+            ControlFlow::Continue(())
+        })?;
+    }
+    Ok((buckets, truncated))
+}
+
+/// The times of the oldest and newest events we have, so consumers can bound their queries.
+///
+/// The oldest is cheap: the first item of the oldest file. Finding the newest means decoding
+/// the whole of the newest (usually live) file, as archiv can only be read forwards; that's
+/// bounded by the rotation policy, so we don't bother falling back to file times. With
+/// `BATCHY_SHARDS`, any file could have the newest, so every file is decoded, which is slow.
+pub async fn range(State(state): State<Arc<Output>>) -> (StatusCode, Json<Value>) {
+    okay_or_500(&state.logger, || async {
+        let output = Arc::clone(&state);
+        let (oldest, newest) =
+            tokio::task::spawn_blocking(move || oldest_and_newest(&output)).await??;
+        Ok(json!({ "oldest": format_ts(oldest)?, "newest": format_ts(newest)? }))
+    })
+    .await
+}
+
+/// The timestamps of the first and last events, for `range`.
+fn oldest_and_newest(state: &Output) -> Result<(Option<i64>, Option<i64>)> {
+    let files = event_files(&state.config.data_dir, &state.logger)?;
+
+    let mut oldest = None;
+    for file in &files {
+        for_each_item(&file.path, |ts, _| {
+            oldest = Some(ts.secs());
+            ControlFlow::Break(())
+        })?;
+        if oldest.is_some() {
+            break;
+        }
+    }
+
+    let mut newest = None;
+    for file in files.iter().rev() {
+        let mut last = None;
+        for_each_item(&file.path, |ts, _| {
+            last = Some(ts.secs());
+            ControlFlow::Continue(())
+        })?;
+        newest = newest.max(last);
+        if newest.is_some() && state.config.shards == 1 {
+            break;
+        }
+    }
+    Ok((oldest, newest))
+}
+
+pub fn format_ts(ts: Option<i64>) -> Result<Option<String>> {
+    Ok(match ts {
+        Some(ts) => Some(OffsetDateTime::from_unix_timestamp(ts)?.format(&Rfc3339)?),
+        None => None,
+    })
+}
+
+/// Find the files to read on a blocking thread, before we start responding, so failing is
+/// still a 500; a big data dir is a lot of `read_dir` and `stat`.
+async fn list_blocking(
+    state: &Arc<Output>,
+    list: impl FnOnce(&Output) -> Result<Vec<EventFile>> + Send + 'static,
+) -> Result<Vec<EventFile>> {
+    let state = Arc::clone(state);
+    tokio::task::spawn_blocking(move || list(&state)).await?
+}
+
+/// Stream newline-delimited JSON, as generated (on a blocking thread) by `produce`, which is
+/// handed a function to emit each line. Emitting breaks if the client has gone away.
+fn ndjson_stream<P>(state: Arc<Output>, produce: P) -> Response
+where
+    P: FnOnce(&mut dyn FnMut(Value) -> ControlFlow<()>) -> Result<()> + Send + 'static,
+{
+    blocking_stream(state, "application/x-ndjson", move |emit| {
+        produce(&mut |line: Value| {
+            let mut line = match serde_json::to_vec(&line) {
+                Ok(line) => line,
+                Err(_) => return ControlFlow::Break(()),
+            };
+            line.push(b'\n');
+            emit(line)
+        })
+    })
+}
+
+/// Stream a body generated on a blocking thread by `produce`, which is handed a function to
+/// emit each chunk. Emitting breaks if the client has gone away.
+///
+/// Errors part way through can't change the status, so they abort the response instead.
+fn blocking_stream<P>(state: Arc<Output>, content_type: &'static str, produce: P) -> Response
+where
+    P: FnOnce(&mut dyn FnMut(Vec<u8>) -> ControlFlow<()>) -> Result<()> + Send + 'static,
+{
+    let (mut tx, body) = Body::channel();
+    let handle = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        let mut emit = |chunk: Vec<u8>| match handle.block_on(tx.send_data(chunk.into())) {
+            Ok(()) => ControlFlow::Continue(()),
+            Err(_) => ControlFlow::Break(()),
+        };
+        if let Err(err) = produce(&mut emit) {
+            state
+                .logger
+                .error(vars_dbg!(err), "error streaming response");
+            tx.abort();
+        }
+    });
+    ([(CONTENT_TYPE, content_type)], body::boxed(body)).into_response()
+}
+
+/// Adapts a `blocking_stream` emitter for things which want to `Write`.
+struct EmitWriter<'e>(&'e mut dyn FnMut(Vec<u8>) -> ControlFlow<()>);
+
+impl io::Write for EmitWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match (self.0)(buf.to_vec()) {
+            ControlFlow::Continue(()) => Ok(buf.len()),
+            ControlFlow::Break(()) => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Validate an api name, and find its file, or an error response.
+fn existing_file(state: &Output, name: &str) -> Result<PathBuf, (StatusCode, Json<Value>)> {
+    let Some(path) = name::path(&state.config.data_dir, name) else {
+        return Err(bad_request("invalid name"));
+    };
+    if !fs::metadata(&path).is_ok_and(|m| m.is_file()) {
+        return Err(json_error(StatusCode::NOT_FOUND, "no such file"));
+    }
+    Ok(path)
+}
+
+/// How the text read-back endpoints present payloads which aren't UTF-8.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Binary {
+    /// as base64, marked `"encoding": "base64"`
+    Base64,
+    /// with invalid sequences replaced by U+FFFD, marked `"encoding": "lossy"`
+    Lossy,
+    /// not at all
+    Skip,
+}
+
+impl FromStr for Binary {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "base64" => Binary::Base64,
+            "lossy" => Binary::Lossy,
+            "skip" => Binary::Skip,
+            _ => return Err("expected one of: base64, lossy, skip"),
+        })
+    }
+}
+
+/// An event as a read-back record, `{"ts": .., "body": ..}`, with an `encoding` if the body
+/// wasn't UTF-8, or None if the policy is to skip it.
+fn event_record(ts: i64, body: &[u8], binary: Binary) -> Option<Value> {
+    let (body, encoding) = match (std::str::from_utf8(body), binary) {
+        (Ok(body), _) => return Some(json!({ "ts": ts, "body": body })),
+        (Err(_), Binary::Skip) => return None,
+        (Err(_), Binary::Base64) => (BASE64.encode(body), "base64"),
+        (Err(_), Binary::Lossy) => (String::from_utf8_lossy(body).into_owned(), "lossy"),
+    };
+    Some(json!({ "ts": ts, "body": body, "encoding": encoding }))
+}
+
+/// As `event_record`, with a `content_type`, if the file kept one for this event, and a
+/// `ts_nanos`, if its file's timestamps are finer than `ts`'s whole seconds.
+fn typed_record(
+    ts: Timestamp,
+    content_type: Option<&[u8]>,
+    body: &[u8],
+    binary: Binary,
+) -> Option<Value> {
+    let mut record = event_record(ts.secs(), body, binary)?;
+    if let Some(nanos) = ts.finer_nanos() {
+        record["ts_nanos"] = nanos.into();
+    }
+    if let Some(content_type) = content_type.filter(|v| !v.is_empty()) {
+        record["content_type"] = String::from_utf8_lossy(content_type).into();
+    }
+    Some(record)
+}
+
+/// How many events a read endpoint should return: what the client asked for, but never more
+/// than the configured maximum.
+fn read_limit(state: &Output, limit: Option<u64>) -> u64 {
+    let max = state.config.max_read_items;
+    limit.map_or(max, |limit| limit.min(max))
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    field: String,
+    value: String,
+    limit: Option<u64>,
+}
+
+/// The JSON events in a file with a top-level `field` equal to `value`, as NDJSON.
+///
+/// Strings are compared directly, anything else with `value` parsed as JSON, so `value=true`
+/// matches `{"field": true}`. Non-JSON events are skipped. At most `MAX_SCANNED` events are
+/// looked at, and `limit` returned; if we stopped early, the last line is `{"truncated": true}`.
+pub async fn search(
+    State(state): State<Arc<Output>>,
+    NameParam(name): NameParam,
+    Query(query): Query<SearchQuery>,
+) -> Response {
+    let path = match existing_file(&state, &name) {
+        Ok(path) => path,
+        Err(resp) => return resp.into_response(),
+    };
+
+    // so `value=true` can match a boolean, as well as the string
+    let as_json = serde_json::from_str::<Value>(&query.value).ok();
+    let limit = read_limit(&state, query.limit);
+
+    ndjson_stream(state, move |emit| {
+        let mut scanned = 0u64;
+        let mut returned = 0u64;
+        let mut truncated = false;
+        let mut stopped = false;
+        for_each_item(&path, |ts, body| {
+            if scanned == MAX_SCANNED || returned == limit {
+                truncated = true;
+                return ControlFlow::Break(());
+            }
+            scanned += 1;
+            let event = match serde_json::from_slice::<Value>(body) {
+                Ok(event) => event,
+                Err(_) => return ControlFlow::Continue(()),
+            };
+            let matched = match event.get(&query.field) {
+                Some(Value::String(s)) => *s == query.value,
+                Some(other) => as_json.as_ref() == Some(other),
+                None => false,
+            };
+            if !matched {
+                return ControlFlow::Continue(());
+            }
+            returned += 1;
+            let mut record = json!({ "ts": ts.secs(), "body": event });
+            if let Some(nanos) = ts.finer_nanos() {
+                record["ts_nanos"] = nanos.into();
+            }
+            let flow = emit(record);
+            stopped = flow.is_break();
+            flow
+        })?;
+        if truncated && !stopped {
+            let _ = emit(json!({ "truncated": true }));
+        }
+        Ok(())
+    })
+}
+
+/// The header the file's first writer sent, verbatim.
+pub async fn fetch_header(
+    State(state): State<Arc<Output>>,
+    NameParam(name): NameParam,
+) -> Response {
+    let path = match existing_file(&state, &name) {
+        Ok(path) => path,
+        Err(resp) => return resp.into_response(),
+    };
+    match header(&path) {
+        Ok(Some(header)) => header.into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "file has no header").into_response(),
+        Err(err) => internal_error(&state.logger, err).into_response(),
+    }
+}
+
+/// The file's size on disk, and the total of its items, decoding it if needed.
+///
+/// Decoding a file to find its uncompressed size is slow, so that's remembered for as long as
+/// the file's modification time doesn't change. The live file's changes with every flush, so it
+/// isn't worth remembering; its size is only as of the last flush anyway.
+async fn sizes(state: &Output, path: &Path) -> Result<(u64, u64)> {
+    let meta = fs::metadata(path)?;
+    let compressed = meta.len();
+    let modified = meta.modified()?;
+    let cached = state
+        .ratio_cache
+        .lock()
+        .expect("not poisoned")
+        .get(path)
+        .filter(|(when, _)| *when == modified)
+        .map(|(_, size)| *size);
+    if let Some(size) = cached {
+        return Ok((compressed, size));
+    }
+    let owned = path.to_path_buf();
+    let size = tokio::task::spawn_blocking(move || uncompressed_size(&owned)).await??;
+    if !state.is_live(path) {
+        state
+            .ratio_cache
+            .lock()
+            .expect("not poisoned")
+            .insert(path.to_path_buf(), (modified, size));
+    }
+    Ok((compressed, size))
+}
+
+/// How well a file compressed, for deciding whether a higher level is worth the CPU.
+pub async fn ratio(
+    State(state): State<Arc<Output>>,
+    NameParam(name): NameParam,
+) -> (StatusCode, Json<Value>) {
+    let path = match existing_file(&state, &name) {
+        Ok(path) => path,
+        Err(resp) => return resp,
+    };
+
+    okay_or_500(&state.logger, || async {
+        let (compressed, uncompressed) = sizes(&state, &path).await?;
+        let ratio = match compressed {
+            0 => None,
+            compressed => Some(uncompressed as f64 / compressed as f64),
+        };
+        Ok(json!({
+            "compressed_bytes": compressed,
+            "uncompressed_bytes": uncompressed,
+            "ratio": ratio,
+        }))
+    })
+    .await
+}
+
+/// How much there is to download, raw or decompressed, so clients can pick.
+///
+/// `decompressed_bytes` counts each item's timestamp and payload, i.e. what the read-back
+/// endpoints have to work with, before their own formatting. For the live file, both are only
+/// as of the last flush, which is reported as `"live": true`.
+pub async fn size(
+    State(state): State<Arc<Output>>,
+    NameParam(name): NameParam,
+) -> (StatusCode, Json<Value>) {
+    let path = match existing_file(&state, &name) {
+        Ok(path) => path,
+        Err(resp) => return resp,
+    };
+
+    okay_or_500(&state.logger, || async {
+        let live = state.is_live(&path);
+        let (compressed, decompressed) = sizes(&state, &path).await?;
+        Ok(json!({
+            "compressed_bytes": compressed,
+            "decompressed_bytes": decompressed,
+            "live": live,
+        }))
+    })
+    .await
+}
+
+/// A file's events, for clients which can't cope with archiv, or zstd in general.
+///
+/// `/api/events/<name>.gz` is a gzip stream of records of `len(8) ++ timestamp(8) ++ payload`,
+/// both little-endian, where `len` counts the timestamp and payload, i.e. archiv's own framing,
+/// without its header or footer. The timestamps are as the file has them: whole seconds,
+/// unless it has a precision marker (see `/api/format`).
+///
+/// `/api/events/<name>` is a JSON array of `{"timestamp": .., "body": ..}`, with an `encoding`
+/// for payloads which aren't UTF-8, as `BATCHY_BINARY` says. It's built in memory, so stops
+/// after `BATCHY_MAX_READ_ITEMS` events, with a last element of `{"truncated": true}`.
+///
+/// Either can be limited to the events between `from` and `to` (inclusive, either optional).
+/// Items are in the order they arrived, so reading stops at the first one after `to`.
+pub async fn events(
+    State(state): State<Arc<Output>>,
+    NameParam(name): NameParam,
+    Query(query): Query<EventsQuery>,
+) -> Response {
+    let range = match TimeRange::parse(query.from.as_deref(), query.to.as_deref()) {
+        Ok(range) => range,
+        Err(resp) => return resp.into_response(),
+    };
+    let name = match name.strip_suffix(".gz") {
+        Some(name) => name,
+        None => return events_json(state, &name, range).await.into_response(),
+    };
+    let path = match existing_file(&state, name) {
+        Ok(path) => path,
+        Err(resp) => return resp.into_response(),
+    };
+
+    blocking_stream(state, "application/gzip", move |emit| {
+        let mut out = GzEncoder::new(EmitWriter(emit), Compression::default());
+        let mut failure = None;
+        for_each_item(&path, |ts, body| {
+            if range.is_after(ts.secs()) {
+                return ControlFlow::Break(());
+            }
+            if !range.contains(ts.secs()) {
+                return ControlFlow::Continue(());
+            }
+            let len = 8 + body.len() as u64;
+            let written = out
+                .write_all(&len.to_le_bytes())
+                .and_then(|()| out.write_all(&ts.value.to_le_bytes()))
+                .and_then(|()| out.write_all(body));
+            match written {
+                Ok(()) => ControlFlow::Continue(()),
+                Err(err) => {
+                    failure = Some(err);
+                    ControlFlow::Break(())
+                }
+            }
+        })?;
+        match failure {
+            // they hung up; there's nobody to tell
+            Some(err) if err.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            Some(err) => return Err(err.into()),
+            None => (),
+        }
+        match out.finish() {
+            Err(err) if err.kind() != io::ErrorKind::BrokenPipe => Err(err.into()),
+            _ => Ok(()),
+        }
+    })
+}
+
+#[derive(Deserialize)]
+pub struct RecentQuery {
+    files: Option<usize>,
+    live: Option<bool>,
+    limit: Option<u64>,
+    binary: Option<Binary>,
+}
+
+/// The events from the newest few `files` (default 1), oldest first, as NDJSON; roughly "the
+/// last few hours", without the client having to work out which files those are.
+///
+/// The live file is included (and counts as one of the files) unless `live=false`. It's flushed
+/// after every store, so this sees everything stored before the request, except with
+/// `BATCHY_DURABILITY=buffered`, where the latest events may not show up until a later store
+/// flushes, or the file is finished. Like `search`, this stops after `limit` events, with a
+/// last line of `{"truncated": true}`.
+pub async fn recent(
+    State(state): State<Arc<Output>>,
+    Query(query): Query<RecentQuery>,
+) -> Response {
+    let count = query.files.unwrap_or(1);
+    if !(1..=MAX_RECENT_FILES).contains(&count) {
+        return bad_request("files must be between 1 and 100").into_response();
+    }
+    let mut files = match list_blocking(&state, |state| {
+        event_files(&state.config.data_dir, &state.logger)
+    })
+    .await
+    {
+        Ok(files) => files,
+        Err(err) => return internal_error(&state.logger, err).into_response(),
+    };
+    if query.live == Some(false) {
+        files.retain(|f| !state.is_live(&f.path));
+    }
+    let skip = files.len().saturating_sub(count);
+    let files = files.split_off(skip);
+    let limit = read_limit(&state, query.limit);
+    let binary = query.binary.unwrap_or(state.config.binary);
+
+    ndjson_stream(state, move |emit| {
+        let mut returned = 0u64;
+        let mut truncated = false;
+        for file in files {
+            let mut stopped = false;
+            for_each_item(&file.path, |ts, body| {
+                if returned == limit {
+                    truncated = true;
+                    return ControlFlow::Break(());
+                }
+                let record = match typed_record(ts, None, body, binary) {
+                    Some(record) => record,
+                    None => return ControlFlow::Continue(()),
+                };
+                returned += 1;
+                let flow = emit(record);
+                stopped = flow.is_break();
+                flow
+            })?;
+            if stopped {
+                return Ok(());
+            }
+            if truncated {
+                let _ = emit(json!({ "truncated": true }));
+                return Ok(());
+            }
+        }
+        Ok(())
+    })
+}
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    from: Option<String>,
+    to: Option<String>,
+}
+
+/// The unix times an event's timestamp must be between, inclusive; either end can be open.
+#[derive(Copy, Clone)]
+struct TimeRange {
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+impl TimeRange {
+    /// From RFC3339 query parameters, or a 400 if they're invalid, or backwards.
+    fn parse(from: Option<&str>, to: Option<&str>) -> Result<TimeRange, (StatusCode, Json<Value>)> {
+        let from = match from.map(parse_date) {
+            Some(None) => return Err(bad_request("invalid from")),
+            from => from.flatten().map(|v| v.unix_timestamp()),
+        };
+        let to = match to.map(parse_date) {
+            Some(None) => return Err(bad_request("invalid to")),
+            to => to.flatten().map(|v| v.unix_timestamp()),
+        };
+        if let (Some(from), Some(to)) = (from, to) {
+            if from > to {
+                return Err(bad_request("from is after to"));
+            }
+        }
+        Ok(TimeRange { from, to })
+    }
+
+    fn contains(&self, ts: i64) -> bool {
+        self.from.is_none_or(|from| ts >= from) && !self.is_after(ts)
+    }
+
+    fn is_after(&self, ts: i64) -> bool {
+        self.to.is_some_and(|to| ts > to)
+    }
+}
+
+async fn events_json(
+    state: Arc<Output>,
+    name: &str,
+    range: TimeRange,
+) -> (StatusCode, Json<Value>) {
+    let path = match existing_file(&state, name) {
+        Ok(path) => path,
+        Err(resp) => return resp,
+    };
+    let limit = state.config.max_read_items;
+    let binary = state.config.binary;
+    // the whole array is built before we can respond, so it all happens on a blocking thread
+    let collected =
+        tokio::task::spawn_blocking(move || collect_events(&path, range, limit, binary));
+    match collected.await.map_err(anyhow::Error::from).and_then(|v| v) {
+        Ok(events) => (StatusCode::OK, Json(Value::Array(events))),
+        Err(err) => internal_error(&state.logger, err),
+    }
+}
+
+/// The events for `events_json`, and a `{"truncated": true}` if there were too many.
+fn collect_events(path: &Path, range: TimeRange, limit: u64, binary: Binary) -> Result<Vec<Value>> {
+    let mut events = Vec::new();
+    let mut truncated = false;
+    for_each_item(path, |ts, body| {
+        if range.is_after(ts.secs()) {
+            return ControlFlow::Break(());
+        }
+        if !range.contains(ts.secs()) {
+            return ControlFlow::Continue(());
+        }
+        if events.len() as u64 == limit {
+            truncated = true;
+            return ControlFlow::Break(());
+        }
+        if let Some(mut record) = typed_record(ts, None, body, binary) {
+            // spelt out here, unlike the NDJSON endpoints
+            if let Some(ts) = record.as_object_mut().and_then(|r| r.remove("ts")) {
+                record["timestamp"] = ts;
+            }
+            events.push(record);
+        }
+        ControlFlow::Continue(())
+    })?;
+    if truncated {
+        events.push(json!({ "truncated": true }));
+    }
+    Ok(events)
+}
+
+#[derive(Deserialize)]
+pub struct BetweenQuery {
+    from: Option<String>,
+    to: Option<String>,
+    limit: Option<u64>,
+    binary: Option<Binary>,
+}
+
+/// The events stored between `from` and `to` (inclusive, either optional), across however many
+/// files that takes, oldest first, as NDJSON records; like `recent`, but by time.
+///
+/// Files are picked by their names, then the events by their own timestamps. Damaged items are
+/// logged and skipped, as is the rest of a file which can't be decoded at all, so one bad file
+/// doesn't lose the whole range. Stops after `limit` events, with a last line of
+/// `{"truncated": true}`.
+pub async fn between(
+    State(state): State<Arc<Output>>,
+    Query(query): Query<BetweenQuery>,
+) -> Response {
+    let from = match query.from.as_deref().map(parse_date) {
+        Some(None) => return bad_request("invalid from").into_response(),
+        from => from.flatten(),
+    };
+    let to = match query.to.as_deref().map(parse_date) {
+        Some(None) => return bad_request("invalid to").into_response(),
+        to => to.flatten(),
+    };
+    let files = match list_blocking(&state, move |state| {
+        files_overlapping(
+            &state.config.data_dir,
+            &state.logger,
+            state.config.shards,
+            from,
+            to,
+        )
+    })
+    .await
+    {
+        Ok(files) => files,
+        Err(err) => return internal_error(&state.logger, err).into_response(),
+    };
+    let from_ts = from.map(|v| v.unix_timestamp());
+    let to_ts = to.map(|v| v.unix_timestamp());
+    let limit = read_limit(&state, query.limit);
+    let binary = query.binary.unwrap_or(state.config.binary);
+
+    let output = Arc::clone(&state);
+    ndjson_stream(state, move |emit| {
+        let logger = &output.logger;
+        let mut returned = 0u64;
+        let mut truncated = false;
+        for file in files {
+            let file_name = file.file_name;
+            let mut stopped = false;
+            let malformed = |len: usize| {
+                logger.warn(vars!(file_name, len), "skipping malformed item");
+                Ok(())
+            };
+            let walked = walk_events_with(&file.path, malformed, |ts, content_type, body| {
+                let secs = ts.secs();
+                if from_ts.is_some_and(|from| secs < from) || to_ts.is_some_and(|to| secs > to) {
+                    return ControlFlow::Continue(());
+                }
+                if returned == limit {
+                    truncated = true;
+                    return ControlFlow::Break(());
+                }
+                let record = match typed_record(ts, content_type, body, binary) {
+                    Some(record) => record,
+                    None => return ControlFlow::Continue(()),
+                };
+                returned += 1;
+                let flow = emit(record);
+                stopped = flow.is_break();
+                flow
+            });
+            if let Err(err) = walked {
+                logger.warn(
+                    vars_dbg!(file_name, err),
+                    "skipping rest of unreadable file",
+                );
+            }
+            if stopped {
+                return Ok(());
+            }
+            if truncated {
+                let _ = emit(json!({ "truncated": true }));
+                return Ok(());
+            }
+        }
+        Ok(())
+    })
+}
+
+#[derive(Deserialize)]
+pub struct ReadQuery {
+    format: Option<String>,
+    flatten: Option<bool>,
+    limit: Option<u64>,
+    binary: Option<Binary>,
+}
+
+/// A file's events, as NDJSON records (the default), or as CSV for spreadsheets.
+///
+/// CSV rows are `ts,body,encoding,ts_nanos`, where `encoding` is only set for payloads which
+/// weren't UTF-8, and `ts_nanos` for files with finer timestamps than seconds. With
+/// `flatten=true`, the columns are instead `ts`, every top-level field of the JSON objects,
+/// and `ts_nanos`, and anything else is skipped. Either way, at most `limit` events are returned;
+/// if there were more, the last line is `{"truncated": true}`, or a CSV row of `truncated`.
+pub async fn read_file(
+    State(state): State<Arc<Output>>,
+    NameParam(name): NameParam,
+    Query(query): Query<ReadQuery>,
+) -> Response {
+    let path = match existing_file(&state, &name) {
+        Ok(path) => path,
+        Err(resp) => return resp.into_response(),
+    };
+    let limit = read_limit(&state, query.limit);
+    let binary = query.binary.unwrap_or(state.config.binary);
+
+    match query.format.as_deref().unwrap_or("ndjson") {
+        "ndjson" => ndjson_records(state, path, limit, binary),
+        "csv" => {
+            let flatten = query.flatten.unwrap_or(false);
+            let mut resp =
+                blocking_stream(
+                    state,
+                    "text/csv; charset=utf-8",
+                    move |emit| match flatten {
+                        true => flat_csv(&path, limit, emit),
+                        false => plain_csv(&path, limit, binary, emit),
+                    },
+                );
+            // colons upset some operating systems
+            let disposition = format!("attachment; filename=\"{}.csv\"", name.replace(':', "-"));
+            if let Ok(disposition) = disposition.parse() {
+                resp.headers_mut().insert(CONTENT_DISPOSITION, disposition);
+            }
+            resp
+        }
+        _ => bad_request("format must be one of: ndjson, csv").into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ItemsQuery {
+    limit: Option<u64>,
+    binary: Option<Binary>,
+}
+
+/// A file's events, decoded, as NDJSON records, without the client needing archiv; the same as
+/// `/api/read/:name` gives by default, but next to the raw file it came from.
+pub async fn items(
+    State(state): State<Arc<Output>>,
+    NameParam(name): NameParam,
+    Query(query): Query<ItemsQuery>,
+) -> Response {
+    let path = match existing_file(&state, &name) {
+        Ok(path) => path,
+        Err(resp) => return resp.into_response(),
+    };
+    let limit = read_limit(&state, query.limit);
+    let binary = query.binary.unwrap_or(state.config.binary);
+    ndjson_records(state, path, limit, binary)
+}
+
+/// Stream the file's events as NDJSON records, stopping after `limit`, with a last line of
+/// `{"truncated": true}` if there were more.
+fn ndjson_records(state: Arc<Output>, path: PathBuf, limit: u64, binary: Binary) -> Response {
+    ndjson_stream(state, move |emit| {
+        let mut returned = 0u64;
+        let mut truncated = false;
+        let mut stopped = false;
+        walk_events(&path, |ts, content_type, body| {
+            if returned == limit {
+                truncated = true;
+                return ControlFlow::Break(());
+            }
+            let record = match typed_record(ts, content_type, body, binary) {
+                Some(record) => record,
+                None => return ControlFlow::Continue(()),
+            };
+            returned += 1;
+            let flow = emit(record);
+            stopped = flow.is_break();
+            flow
+        })?;
+        if truncated && !stopped {
+            let _ = emit(json!({ "truncated": true }));
+        }
+        Ok(())
+    })
+}
+
+fn plain_csv(
+    path: &Path,
+    limit: u64,
+    binary: Binary,
+    emit: &mut dyn FnMut(Vec<u8>) -> ControlFlow<()>,
+) -> Result<()> {
+    if emit(csv_row(["ts", "body", "encoding", "ts_nanos"])).is_break() {
+        return Ok(());
+    }
+    let mut returned = 0u64;
+    let mut truncated = false;
+    let mut stopped = false;
+    for_each_item(path, |ts, body| {
+        if returned == limit {
+            truncated = true;
+            return ControlFlow::Break(());
+        }
+        let record = match event_record(ts.secs(), body, binary) {
+            Some(record) => record,
+            None => return ControlFlow::Continue(()),
+        };
+        returned += 1;
+        let body = record["body"].as_str().unwrap_or_default();
+        let encoding = record["encoding"].as_str().unwrap_or_default();
+        let nanos = ts.finer_nanos().map(|v| v.to_string()).unwrap_or_default();
+        let flow = emit(csv_row([&ts.secs().to_string(), body, encoding, &nanos]));
+        stopped = flow.is_break();
+        flow
+    })?;
+    if truncated && !stopped {
+        let _ = emit(csv_row(["truncated"]));
+    }
+    Ok(())
+}
+
+/// Two passes: the first to find the columns, as they have to be in the first row.
+fn flat_csv(
+    path: &Path,
+    limit: u64,
+    emit: &mut dyn FnMut(Vec<u8>) -> ControlFlow<()>,
+) -> Result<()> {
+    let mut columns = Vec::<String>::new();
+    let mut seen = 0u64;
+    for_each_item(path, |_, body| {
+        if seen == limit {
+            return ControlFlow::Break(());
+        }
+        if let Ok(Value::Object(event)) = serde_json::from_slice(body) {
+            seen += 1;
+            for key in event.keys() {
+                if !columns.contains(key) {
+                    columns.push(key.to_string());
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    })?;
+
+    let header = std::iter::once("ts")
+        .chain(columns.iter().map(String::as_str))
+        .chain(["ts_nanos"]);
+    if emit(csv_row(header)).is_break() {
+        return Ok(());
+    }
+    let mut returned = 0u64;
+    let mut truncated = false;
+    let mut stopped = false;
+    for_each_item(path, |ts, body| {
+        let event = match serde_json::from_slice(body) {
+            Ok(Value::Object(event)) => event,
+            _ => return ControlFlow::Continue(()),
+        };
+        if returned == limit {
+            truncated = true;
+            return ControlFlow::Break(());
+        }
+        returned += 1;
+        let mut row = vec![ts.secs().to_string()];
+        for column in &columns {
+            row.push(match event.get(column) {
+                None | Some(Value::Null) => String::new(),
+                Some(Value::String(s)) => s.to_string(),
+                Some(other) => other.to_string(),
+            });
+        }
+        row.push(ts.finer_nanos().map(|v| v.to_string()).unwrap_or_default());
+        let flow = emit(csv_row(row.iter().map(String::as_str)));
+        stopped = flow.is_break();
+        flow
+    })?;
+    if truncated && !stopped {
+        let _ = emit(csv_row(["truncated"]));
+    }
+    Ok(())
+}
+
+/// A line of RFC 4180 CSV, quoting only the fields which need it.
+fn csv_row<'f>(fields: impl IntoIterator<Item = &'f str>) -> Vec<u8> {
+    let mut row = String::new();
+    for (i, field) in fields.into_iter().enumerate() {
+        if i > 0 {
+            row.push(',');
+        }
+        if field.contains([',', '"', '\n', '\r']) {
+            row.push('"');
+            row.push_str(&field.replace('"', "\"\""));
+            row.push('"');
+        } else {
+            row.push_str(field);
+        }
+    }
+    row.push_str("\r\n");
+    row.into_bytes()
+}
+
+#[derive(Deserialize)]
+pub struct TailQuery {
+    n: Option<u64>,
+    binary: Option<Binary>,
+}
+
+/// The last `n` (default 100) events, oldest first, as NDJSON.
+///
+/// These normally come from the in-memory buffer of recent events, but if that doesn't have
+/// enough (e.g. we've just started), the files are read instead, newest first, which is slow.
+pub async fn tail(State(state): State<Arc<Output>>, Query(query): Query<TailQuery>) -> Response {
+    let n = read_limit(&state, Some(query.n.unwrap_or(100)));
+    let binary = query.binary.unwrap_or(state.config.binary);
+    let buffered = {
+        let recent = state.recent.lock().expect("not poisoned");
+        let len = recent.len();
+        match usize::try_from(n) {
+            Ok(n) if n <= len => Some(recent.range(len - n..).cloned().collect::<Vec<_>>()),
+            _ => None,
+        }
+    };
+    let files = match buffered {
+        Some(_) => Vec::new(),
+        None => match list_blocking(&state, |state| {
+            event_files(&state.config.data_dir, &state.logger)
+        })
+        .await
+        {
+            Ok(files) => files,
+            Err(err) => return internal_error(&state.logger, err).into_response(),
+        },
+    };
+
+    ndjson_stream(state, move |emit| {
+        let events = match buffered {
+            Some(events) => events,
+            None => tail_files(&files, n)?,
+        };
+        for (ts, body) in events {
+            if let Some(record) = typed_record(ts, None, &body, binary) {
+                if emit(record).is_break() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// The last `n` events in the files, oldest first.
+fn tail_files(files: &[EventFile], n: u64) -> Result<Vec<(Timestamp, Bytes)>> {
+    let n = usize::try_from(n)?;
+    let mut events = VecDeque::with_capacity(n);
+    for file in files.iter().rev() {
+        if events.len() == n {
+            break;
+        }
+        let wanted = n - events.len();
+        let mut last = VecDeque::with_capacity(wanted);
+        for_each_item(&file.path, |ts, body| {
+            if last.len() == wanted {
+                last.pop_front();
+            }
+            last.push_back((ts, Bytes::copy_from_slice(body)));
+            ControlFlow::Continue(())
+        })?;
+        for event in last.into_iter().rev() {
+            events.push_front(event);
+        }
+    }
+    Ok(events.into())
+}
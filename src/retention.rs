@@ -0,0 +1,140 @@
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bunyarrs::{vars, vars_dbg};
+use time::OffsetDateTime;
+
+use crate::admin::{event_files, EventFile};
+use crate::{disk, Output};
+
+/// How often we look for files to delete; the window is expected to be days, not minutes.
+const INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Delete finished files whose names say they were started more than `keep` ago, on startup,
+/// and every `INTERVAL` after that. Live files are never deleted, however old they are.
+pub async fn expire(output: Arc<Output>, keep: Duration) {
+    let retention_secs = keep.as_secs();
+    let logger = &output.scheduler_logger;
+    logger.info(vars!(retention_secs), "retention started");
+    let mut interval = tokio::time::interval(INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let live = live_file_names(&output).await;
+        // a big data dir is a lot of `read_dir`, and deleting isn't free either
+        if tokio::task::block_in_place(|| delete_expired(&output, &live, keep)) {
+            output.catalog_stale.notify_one();
+        }
+    }
+}
+
+/// The body of `expire`: returns whether anything was deleted.
+fn delete_expired(output: &Output, live: &[String], keep: Duration) -> bool {
+    let logger = &output.scheduler_logger;
+    let files = match event_files(&output.config.data_dir, logger) {
+        Ok(files) => files,
+        Err(err) => {
+            logger.error(vars_dbg!(err), "unable to list files for retention");
+            return false;
+        }
+    };
+
+    let now = OffsetDateTime::now_utc();
+    let mut deleted = false;
+    for file in files {
+        let age = now - file.start;
+        if live.contains(&file.file_name) || age < keep {
+            continue;
+        }
+        let file_name = &file.file_name;
+        let age_secs = age.whole_seconds();
+        match remove(output, &file) {
+            Ok(()) => {
+                logger.info(vars!(file_name, age_secs), "deleted expired file");
+                deleted = true;
+            }
+            Err(err) => {
+                logger.error(
+                    vars_dbg!(file_name, age_secs, err),
+                    "unable to delete expired file",
+                );
+            }
+        }
+    }
+    deleted
+}
+
+/// Delete finished files, oldest first, until there's `BATCHY_MIN_FREE_BYTES` free again, or
+/// only the live files are left. Returns how much is free afterwards.
+pub async fn evict(output: &Output, free_bytes: u64) -> u64 {
+    let live = live_file_names(output).await;
+    // as in `expire`
+    tokio::task::block_in_place(|| evict_files(output, &live, free_bytes))
+}
+
+/// The body of `evict`.
+fn evict_files(output: &Output, live: &[String], mut free_bytes: u64) -> u64 {
+    let logger = &output.scheduler_logger;
+    let min_free_bytes = output.config.min_free_bytes;
+    let files = match event_files(&output.config.data_dir, logger) {
+        Ok(files) => files,
+        Err(err) => {
+            logger.error(vars_dbg!(err), "unable to list files for eviction");
+            return free_bytes;
+        }
+    };
+
+    let mut deleted = false;
+    for file in files {
+        if free_bytes >= min_free_bytes {
+            break;
+        }
+        if live.contains(&file.file_name) {
+            continue;
+        }
+        let file_name = &file.file_name;
+        if let Err(err) = remove(output, &file) {
+            logger.error(vars_dbg!(file_name, err), "unable to evict file");
+            continue;
+        }
+        deleted = true;
+        match disk::free_bytes(&output.config.data_dir) {
+            Ok(now) => free_bytes = now,
+            Err(err) => {
+                logger.warn(vars_dbg!(err), "unable to check free disk space");
+                break;
+            }
+        }
+        logger.warn(vars!(file_name, free_bytes, min_free_bytes), "evicted file");
+    }
+    if deleted {
+        output.catalog_stale.notify_one();
+    }
+    free_bytes
+}
+
+/// Files only stop being live, so once we've seen which ones are, it's safe to let go.
+async fn live_file_names(output: &Output) -> Vec<String> {
+    let mut live = Vec::new();
+    for shard in &output.shards {
+        if let Some(writer) = output.lock_writer(shard).await.as_ref() {
+            live.push(writer.name.clone());
+        }
+    }
+    live
+}
+
+/// Delete a finished file, and anything we remember about it.
+fn remove(output: &Output, file: &EventFile) -> std::io::Result<()> {
+    fs::remove_file(&file.path)?;
+    output
+        .ratio_cache
+        .lock()
+        .expect("not poisoned")
+        .remove(&file.path);
+    // as in `delete_file`, the buffer could still have its events
+    output.recent.lock().expect("not poisoned").clear();
+    Ok(())
+}
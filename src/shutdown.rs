@@ -1,5 +1,52 @@
-use bunyarrs::Bunyarr;
+use std::future::Future;
+
+use bunyarrs::vars;
 use tokio::signal;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::log::Logger;
+
+/// The background tasks, so shutdown can stop them, in order, before the live file is
+/// finished; otherwise a rotation could start a new file after we'd finished the last one.
+///
+/// The tasks are stopped at their next await point, which they only reach between units of
+/// work, e.g. never between finishing a file and opening the next. Blocking work has to be done
+/// with `block_in_place`, not `spawn_blocking`, or it would carry on after its task had gone.
+pub struct Tasks {
+    running: Vec<(&'static str, CancellationToken, JoinHandle<()>)>,
+}
+
+impl Tasks {
+    pub fn new() -> Tasks {
+        Tasks {
+            running: Vec::new(),
+        }
+    }
+
+    pub fn spawn(&mut self, name: &'static str, task: impl Future<Output = ()> + Send + 'static) {
+        let stop = CancellationToken::new();
+        let stopped = stop.clone();
+        let handle = tokio::spawn(async move {
+            tokio::select! {
+                _ = stopped.cancelled() => {},
+                _ = task => {},
+            }
+        });
+        self.running.push((name, stop, handle));
+    }
+
+    /// Stop the tasks in the order they were started, each gone before the next is asked.
+    pub async fn stop(self, logger: &Logger) {
+        for (task, stop, handle) in self.running {
+            stop.cancel();
+            if handle.await.is_err() {
+                logger.warn(vars!(task), "shutdown: background task had panicked");
+            }
+            logger.info(vars!(task), "shutdown: background task stopped");
+        }
+    }
+}
 
 pub async fn shutdown_signal() {
     let ctrl_c = async {
@@ -24,6 +71,43 @@ pub async fn shutdown_signal() {
         _ = terminate => {},
     }
 
-    let logger = Bunyarr::with_name("batchy");
+    let logger = Logger::with_name("batchy");
     logger.info((), "signal received, starting graceful shutdown");
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::*;
+
+    // notes when the task it's held by is gone
+    struct Gone(&'static str, Arc<Mutex<Vec<&'static str>>>);
+
+    impl Drop for Gone {
+        fn drop(&mut self) {
+            self.1.lock().unwrap().push(self.0);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn stopped_in_order() {
+        let gone = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = Tasks::new();
+        for name in ["first", "second", "third"] {
+            let guard = Gone(name, Arc::clone(&gone));
+            tasks.spawn(name, async move {
+                let _guard = guard;
+                loop {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            });
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(gone.lock().unwrap().is_empty());
+
+        tasks.stop(&Logger::with_name("test")).await;
+        assert_eq!(*gone.lock().unwrap(), ["first", "second", "third"]);
+    }
+}
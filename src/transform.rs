@@ -0,0 +1,147 @@
+use std::str::FromStr;
+
+use serde_json::Value;
+
+/// Normalisation applied to JSON payloads in `store`, before they're written.
+///
+/// Configured with `BATCHY_TRANSFORM`, a comma-separated list of operations, applied in order,
+/// each to a top-level field of a JSON object:
+///
+///  * `trim:<field>`: strip leading and trailing whitespace from a string field
+///  * `lowercase:<field>`: lowercase a string field
+///  * `drop:<field>`: remove the field entirely
+///
+/// e.g. `BATCHY_TRANSFORM=trim:user,lowercase:user,drop:password`. Missing fields, and fields
+/// of the wrong type, are left alone. When any operation is configured, every payload must be a
+/// JSON object, and anything else is rejected; the payload is stored re-serialised (compacted).
+/// Empty (the default) means payloads are stored untouched.
+#[derive(Default)]
+pub struct Transform {
+    ops: Vec<Op>,
+}
+
+enum Op {
+    Trim(String),
+    Lowercase(String),
+    Drop(String),
+}
+
+impl FromStr for Transform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ops = Vec::new();
+        for op in s.split(',').map(str::trim).filter(|op| !op.is_empty()) {
+            let (kind, field) = op
+                .split_once(':')
+                .ok_or_else(|| format!("{op:?} should look like op:field"))?;
+            let field = field.to_string();
+            ops.push(match kind {
+                "trim" => Op::Trim(field),
+                "lowercase" => Op::Lowercase(field),
+                "drop" => Op::Drop(field),
+                _ => return Err(format!("unknown transform {kind:?}")),
+            });
+        }
+        Ok(Transform { ops })
+    }
+}
+
+impl Transform {
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn apply(&self, body: &[u8]) -> Result<Vec<u8>, &'static str> {
+        if self.is_empty() {
+            return Ok(body.to_vec());
+        }
+        let mut value: Value =
+            serde_json::from_slice(body).map_err(|_| "transform requires a JSON body")?;
+        let obj = value
+            .as_object_mut()
+            .ok_or("transform requires a JSON object")?;
+        for op in &self.ops {
+            match op {
+                Op::Trim(field) => {
+                    if let Some(Value::String(s)) = obj.get_mut(field) {
+                        *s = s.trim().to_string();
+                    }
+                }
+                Op::Lowercase(field) => {
+                    if let Some(Value::String(s)) = obj.get_mut(field) {
+                        *s = s.to_lowercase();
+                    }
+                }
+                Op::Drop(field) => {
+                    obj.remove(field);
+                }
+            }
+        }
+        serde_json::to_vec(&value).map_err(|_| "transform produced invalid JSON")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn apply(spec: &str, body: &str) -> Result<String, &'static str> {
+        let transform: Transform = spec.parse().unwrap();
+        Ok(String::from_utf8(transform.apply(body.as_bytes())?).unwrap())
+    }
+
+    // as JSON, so the fields' order doesn't matter
+    fn apply_json(spec: &str, body: &str) -> Value {
+        serde_json::from_str(&apply(spec, body).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn each_op() {
+        let body = r#"{"user":"  Alice ","n":1}"#;
+        assert_eq!(
+            apply_json("trim:user", body),
+            json!({"user": "Alice", "n": 1})
+        );
+        assert_eq!(
+            apply_json("lowercase:user", body),
+            json!({"user": "  alice ", "n": 1})
+        );
+        assert_eq!(apply_json("drop:user", body), json!({"n": 1}));
+    }
+
+    #[test]
+    fn in_order() {
+        let body = r#"{"user":" Alice ","password":"hunter2"}"#;
+        assert_eq!(
+            apply_json(" trim:user, lowercase:user,,drop:password", body),
+            json!({"user": "alice"})
+        );
+    }
+
+    #[test]
+    fn missing_and_mistyped_fields() {
+        assert_eq!(
+            apply("trim:user,lowercase:n,drop:gone", r#"{"n":1}"#),
+            Ok(r#"{"n":1}"#.into())
+        );
+    }
+
+    #[test]
+    fn bad_specs() {
+        assert!("trim".parse::<Transform>().is_err());
+        assert!("uppercase:user".parse::<Transform>().is_err());
+        assert!("".parse::<Transform>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn not_json() {
+        // untouched if there's nothing to do, and refused if there is
+        assert_eq!(apply("", "not json"), Ok("not json".into()));
+        assert_eq!(apply("", "[1]"), Ok("[1]".into()));
+        assert!(apply("trim:user", "not json").is_err());
+        assert!(apply("trim:user", "[1]").is_err());
+    }
+}
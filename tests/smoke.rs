@@ -1,67 +1,969 @@
+use std::io::{Read as _, Write as _};
+use std::path::Path;
 use std::process::Command;
+use std::sync::{Mutex, MutexGuard};
 use std::time::Duration;
 use std::{fs, io};
 
 use anyhow::Result;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::{json, Value};
 
 #[test]
 fn smoke() -> Result<()> {
-    let home = tempfile::tempdir()?;
-    let mut app = KillOnDrop(
-        Command::new(env!("CARGO_BIN_EXE_batchy"))
-            .current_dir(home.path())
-            .spawn()?,
+    let mut app = Batchy::start()?;
+    ureq::post("http://localhost:3000/store").send_string("hello world")?;
+    ureq::post("http://localhost:3000/store").send_string("goodbye world")?;
+
+    nix::sys::signal::kill(
+        nix::unistd::Pid::from_raw(app.child.0.id().try_into()?),
+        nix::sys::signal::Signal::SIGTERM,
+    )?;
+
+    assert!(app.child.0.wait()?.success());
+    let items = read_events(app.home.path())?
+        .into_iter()
+        .map(|(_, body)| String::from_utf8(body))
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(items, vec!["hello world", "goodbye world"]);
+
+    Ok(())
+}
+
+#[test]
+fn content_types() -> Result<()> {
+    let mut app = Batchy::start_with(&[("BATCHY_KEEP_CONTENT_TYPE", "1")])?;
+    ureq::post("http://localhost:3000/store")
+        .set("content-type", "application/json")
+        .set("x-batchy-file-header", "a header")
+        .send_string(r#"{"hello":"world"}"#)?;
+    ureq::post("http://localhost:3000/store?data=unlabelled").call()?;
+
+    // so the file is finished, and can be read to the end
+    nix::sys::signal::kill(
+        nix::unistd::Pid::from_raw(app.child.0.id().try_into()?),
+        nix::sys::signal::Signal::SIGTERM,
+    )?;
+    assert!(app.child.0.wait()?.success());
+    let events = read_events(app.home.path())?;
+    assert_eq!(
+        events,
+        vec![
+            (
+                Some("application/json".to_string()),
+                br#"{"hello":"world"}"#.to_vec()
+            ),
+            (Some(String::new()), b"unlabelled".to_vec()),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn millisecond_timestamps() -> Result<()> {
+    let app = Batchy::start_with(&[("BATCHY_TIMESTAMP_PRECISION", "millis")])?;
+    ureq::post("http://localhost:3000/store").send_string("hello")?;
+    let name = fs::read_dir(app.home.path())?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .find_map(|name| Some(name.strip_suffix(".events.archiv")?.to_string()))
+        .expect("an event file");
+
+    let record: Value = serde_json::from_str(
+        &ureq::get(&format!("http://localhost:3000/api/read/{name}"))
+            .call()?
+            .into_string()?,
+    )?;
+    let secs = record["ts"].as_i64().expect("seconds");
+    let nanos = record["ts_nanos"].as_i64().expect("nanoseconds");
+    assert_eq!(nanos.div_euclid(1_000_000_000), secs);
+    assert_eq!(nanos % 1_000_000, 0);
+    Ok(())
+}
+
+#[test]
+fn nanosecond_timestamps() -> Result<()> {
+    let _app = Batchy::start_with(&[("BATCHY_TIMESTAMP_PRECISION", "nanos")])?;
+    ureq::post("http://localhost:3000/store").send_string("hello")?;
+
+    // the buffer, then (as it doesn't have five) the files, then the files by name
+    for path in ["/api/tail?n=1", "/api/tail?n=5", "/api/recent"] {
+        let records = ureq::get(&format!("http://localhost:3000{path}"))
+            .call()?
+            .into_string()?;
+        let record: Value = serde_json::from_str(records.lines().next().expect("a record"))?;
+        assert_eq!(record["body"], "hello", "{path}");
+        let secs = record["ts"].as_i64().expect("seconds");
+        let nanos = record["ts_nanos"].as_i64().expect("nanoseconds");
+        assert_eq!(nanos.div_euclid(1_000_000_000), secs, "{path}");
+    }
+    Ok(())
+}
+
+/// Every event in every file, as a consumer decoding the files directly would see them, i.e.
+/// `(content_type, payload)`, where the content type is only there if the file kept them.
+fn read_events(dir: &Path) -> Result<Vec<(Option<String>, Vec<u8>)>> {
+    const HEADER_TS: i64 = i64::MIN;
+    const TYPED_TS: i64 = i64::MIN + 1;
+    const PRECISION_TS: i64 = i64::MIN + 2;
+
+    let mut events = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if !entry.file_type()?.is_file() || !name.to_str().unwrap().ends_with(".events.archiv") {
+            continue;
+        }
+        let opts = archiv::ExpandOptions::default();
+        let mut archiv = opts.stream(io::BufReader::new(fs::File::open(entry.path())?))?;
+        let mut typed = false;
+        while let Some(mut item) = archiv.next_item()? {
+            let mut s = Vec::new();
+            item.read_to_end(&mut s)?;
+            let (ts, rest) = s.split_at(8);
+            match i64::from_le_bytes(ts.try_into()?) {
+                // the timestamps' unit, which we don't look at
+                HEADER_TS | PRECISION_TS => (),
+                TYPED_TS => typed = true,
+                _ if typed => {
+                    let (len, rest) = rest.split_first().expect("content type length");
+                    let (content_type, body) = rest.split_at(usize::from(*len));
+                    events.push((
+                        Some(String::from_utf8(content_type.to_vec())?),
+                        body.to_vec(),
+                    ));
+                }
+                _ => events.push((None, rest.to_vec())),
+            }
+        }
+    }
+    Ok(events)
+}
+
+#[test]
+fn token() -> Result<()> {
+    // and the healthcheck still works without it, or this wouldn't start
+    let _app = Batchy::start_with(&[("BATCHY_TOKEN", "sekrit")])?;
+    let status = |req: ureq::Request| match req.send_string("hello") {
+        Ok(resp) => resp.status(),
+        Err(ureq::Error::Status(status, _)) => status,
+        Err(err) => panic!("{err}"),
+    };
+    let store = || ureq::post("http://localhost:3000/store");
+    assert_eq!(status(store()), 401);
+    assert_eq!(status(store().set("authorization", "Bearer guess")), 401);
+    assert_eq!(status(store().set("authorization", "sekrit")), 401);
+    assert_eq!(status(store().set("authorization", "Bearer sekrit")), 200);
+    assert_eq!(status(ureq::get("http://localhost:3000/api/raw")), 401);
+    assert_eq!(status(ureq::post("http://localhost:3000/api/cycle")), 401);
+    assert_eq!(
+        ureq::get("http://localhost:3000/api/format")
+            .call()?
+            .status(),
+        200
     );
-    let mut tries = 10;
-    loop {
-        if let Some(resp) = ureq::get("http://localhost:3000/healthcheck").call().ok() {
-            assert_eq!(resp.status(), 200);
+    assert_eq!(
+        status(ureq::get("http://localhost:3000/api/raw").set("authorization", "Bearer sekrit")),
+        200
+    );
+    Ok(())
+}
+
+#[test]
+fn unknown_route() -> Result<()> {
+    let _app = Batchy::start()?;
+    match ureq::get("http://localhost:3000/api/no-such-thing").call() {
+        Err(ureq::Error::Status(404, resp)) => {
+            let body: Value = serde_json::from_str(&resp.into_string()?)?;
+            assert_eq!(body, json!({"error": "not found", "code": "NOT_FOUND"}));
+        }
+        other => panic!("expected a 404, got {other:?}"),
+    }
+    Ok(())
+}
+
+#[test]
+fn required_content_type() -> Result<()> {
+    let _app = Batchy::start_with(&[("BATCHY_REQUIRE_CONTENT_TYPE", "application/json")])?;
+    match ureq::post("http://localhost:3000/store")
+        .set("content-type", "text/plain")
+        .send_string("{}")
+    {
+        Err(ureq::Error::Status(415, resp)) => {
+            let body: Value = serde_json::from_str(&resp.into_string()?)?;
+            assert_eq!(body, json!({"error": "unsupported content type"}));
+        }
+        other => panic!("expected a 415, got {other:?}"),
+    }
+    let resp = ureq::post("http://localhost:3000/store")
+        .set("content-type", "application/json; charset=utf-8")
+        .send_string("{}")?;
+    assert_eq!(resp.status(), 200);
+    Ok(())
+}
+
+#[test]
+fn archive() -> Result<()> {
+    let _app = Batchy::start_with(&[("BATCHY_ARCHIVE_DELIMITER", "\\0")])?;
+    // two members, as `cat a.gz b.gz` would make, with a record split across them
+    let mut body = Vec::new();
+    for part in [&b"one\0tw"[..], b"o\0three\0"] {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(part)?;
+        body.extend(gz.finish()?);
+    }
+    let resp: Value = serde_json::from_str(
+        &ureq::post("http://localhost:3000/store/archive")
+            .send_bytes(&body)?
+            .into_string()?,
+    )?;
+    assert_eq!(resp["items"], 3);
+
+    let tail = ureq::get("http://localhost:3000/api/tail?n=3")
+        .call()?
+        .into_string()?;
+    let bodies = tail
+        .lines()
+        .map(|line| Ok(serde_json::from_str::<Value>(line)?["body"].clone()))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(bodies, [json!("one"), json!("two"), json!("three")]);
+    Ok(())
+}
+
+#[test]
+fn batch() -> Result<()> {
+    let _app = Batchy::start()?;
+    let resp: Value = serde_json::from_str(
+        &ureq::post("http://localhost:3000/store/batch")
+            .send_string("{\"n\":1}\r\n\n{\"n\":2}\n")?
+            .into_string()?,
+    )?;
+    assert_eq!(resp["items"], 2);
+
+    let tail = ureq::get("http://localhost:3000/api/tail?n=2")
+        .call()?
+        .into_string()?;
+    let bodies = tail
+        .lines()
+        .map(|line| Ok(serde_json::from_str::<Value>(line)?["body"].clone()))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(bodies, [json!(r#"{"n":1}"#), json!(r#"{"n":2}"#)]);
+
+    let mut frames = Vec::new();
+    for item in [&b"with\nnewline"[..], b""] {
+        frames.extend(u32::try_from(item.len())?.to_le_bytes());
+        frames.extend(item);
+    }
+    let resp: Value = serde_json::from_str(
+        &ureq::post("http://localhost:3000/store/batch?format=frames")
+            .send_bytes(&frames)?
+            .into_string()?,
+    )?;
+    assert_eq!(resp["items"], 2);
+    let tail = ureq::get("http://localhost:3000/api/tail?n=2")
+        .call()?
+        .into_string()?;
+    let bodies = tail
+        .lines()
+        .map(|line| Ok(serde_json::from_str::<Value>(line)?["body"].clone()))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(bodies, [json!("with\nnewline"), json!("")]);
+    Ok(())
+}
+
+#[test]
+fn shards() -> Result<()> {
+    let mut app = Batchy::start_with(&[("BATCHY_SHARDS", "4"), ("BATCHY_DURABILITY", "synced")])?;
+    // enough at once that, with one file, most would be queued behind someone else's fsync
+    let threads = (0..8)
+        .map(|thread| {
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..25 {
+                    ureq::post("http://localhost:3000/store")
+                        .send_string(&format!("{thread}-{i}"))?;
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().expect("no panic")?;
+    }
+
+    let health: Value = serde_json::from_str(
+        &ureq::get("http://localhost:3000/healthcheck")
+            .call()?
+            .into_string()?,
+    )?;
+    assert_eq!(health["live_items"], 200);
+    assert_eq!(health["live_file_names"].as_array().map(Vec::len), Some(4));
+    let rotation: Value = serde_json::from_str(
+        &ureq::get("http://localhost:3000/api/rotation")
+            .call()?
+            .into_string()?,
+    )?;
+    for shard in rotation["progress"].as_array().expect("one per shard") {
+        assert!(shard["items"].as_u64() > Some(0), "every shard was used");
+    }
+
+    nix::sys::signal::kill(
+        nix::unistd::Pid::from_raw(app.child.0.id().try_into()?),
+        nix::sys::signal::Signal::SIGTERM,
+    )?;
+    assert!(app.child.0.wait()?.success());
+    let mut stored = read_events(app.home.path())?
+        .into_iter()
+        .map(|(_, body)| String::from_utf8(body))
+        .collect::<Result<Vec<_>, _>>()?;
+    stored.sort();
+    let mut expected = (0..8)
+        .flat_map(|thread| (0..25).map(move |i| format!("{thread}-{i}")))
+        .collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(stored, expected);
+    Ok(())
+}
+
+#[test]
+fn time_based_cycle() -> Result<()> {
+    let app = Batchy::start_with(&[("BATCHY_CYCLE_SECS", "1")])?;
+    let event_files = || -> Result<usize> {
+        let mut found = 0;
+        for entry in fs::read_dir(app.home.path())? {
+            let name = entry?.file_name();
+            if name.to_str().is_some_and(|n| n.ends_with(".events.archiv")) {
+                found += 1;
+            }
+        }
+        Ok(found)
+    };
+    assert_eq!(event_files()?, 1);
+    std::thread::sleep(Duration::from_millis(2500));
+    assert!(event_files()? >= 2);
+    Ok(())
+}
+
+#[test]
+fn size_based_cycle() -> Result<()> {
+    let app = Batchy::start_with(&[("BATCHY_MAX_FILE_BYTES", "1")])?;
+    for _ in 0..3 {
+        ureq::post("http://localhost:3000/store").send_string("hello")?;
+    }
+    let mut found = 0;
+    for entry in fs::read_dir(app.home.path())? {
+        let name = entry?.file_name();
+        if name.to_str().is_some_and(|n| n.ends_with(".events.archiv")) {
+            found += 1;
+        }
+    }
+    // every store goes over, so each gets a file to itself, plus the new live one
+    assert_eq!(found, 4);
+    Ok(())
+}
+
+#[test]
+fn healthcheck() -> Result<()> {
+    let app = Batchy::start()?;
+    ureq::post("http://localhost:3000/store").send_string("hello")?;
+    let health: Value = serde_json::from_str(
+        &ureq::get("http://localhost:3000/healthcheck")
+            .call()?
+            .into_string()?,
+    )?;
+    assert_eq!(health["ok"], true);
+    let name = health["live_file_name"].as_str().expect("a name");
+    let on_disk = fs::metadata(app.home.path().join(name))?.len();
+    assert_eq!(health["live_bytes"], on_disk);
+    assert!(health["free_bytes"].as_u64().expect("a size") > 0);
+    assert!(health["uptime_secs"].is_u64());
+    Ok(())
+}
+
+#[test]
+fn metrics() -> Result<()> {
+    let _app = Batchy::start()?;
+    ureq::post("http://localhost:3000/store").send_string("hello")?;
+    ureq::post("http://localhost:3000/store").send_string("world")?;
+    ureq::post("http://localhost:3000/api/cycle").call()?;
+    let resp = ureq::get("http://localhost:3000/metrics").call()?;
+    assert_eq!(resp.status(), 200);
+    let body = resp.into_string()?;
+    for line in [
+        "batchy_items_stored_total 2",
+        "batchy_stored_bytes_total 10",
+        "batchy_write_errors_total 0",
+        "batchy_cycles_total 1",
+        "batchy_live_file_items 0",
+        r#"batchy_breaker_state{state="closed"} 1"#,
+    ] {
+        assert!(body.lines().any(|l| l == line), "{line} not in {body}");
+    }
+    for name in [
+        "batchy_live_file_bytes",
+        "batchy_free_bytes",
+        "batchy_recent_buffer_items",
+    ] {
+        assert!(body.contains(&format!("# TYPE {name} gauge")), "{name}");
+    }
+    Ok(())
+}
+
+#[test]
+fn event_based_cycle() -> Result<()> {
+    let _app = Batchy::start_with(&[("BATCHY_MAX_EVENTS", "2")])?;
+    let live_items = || -> Result<u64> {
+        let health: Value = serde_json::from_str(
+            &ureq::get("http://localhost:3000/healthcheck")
+                .call()?
+                .into_string()?,
+        )?;
+        Ok(health["live_items"].as_u64().expect("a count"))
+    };
+    ureq::post("http://localhost:3000/store").send_string("one")?;
+    assert_eq!(live_items()?, 1);
+    ureq::post("http://localhost:3000/store").send_string("two")?;
+    assert_eq!(live_items()?, 0);
+    ureq::post("http://localhost:3000/store").send_string("three")?;
+    assert_eq!(live_items()?, 1);
+    Ok(())
+}
+
+#[test]
+fn retention() -> Result<()> {
+    let data = tempfile::tempdir()?;
+    let recent = (time::OffsetDateTime::now_utc() - Duration::from_secs(60))
+        .format(&time::format_description::well_known::Rfc3339)?;
+    let old = data.path().join("2020-01-01T00:00:00Z.events.archiv");
+    let recent = data.path().join(format!("{recent}.events.archiv"));
+    fs::write(&old, b"")?;
+    fs::write(&recent, b"")?;
+
+    let data_dir = data.path().to_str().expect("utf-8 temp dir");
+    let _app = Batchy::start_with(&[("BATCHY_DATA_DIR", data_dir), ("BATCHY_RETENTION", "1day")])?;
+    // it runs as soon as it starts, but that's not necessarily before we get here
+    for _ in 0..20 {
+        if !old.exists() {
             break;
         }
         std::thread::sleep(Duration::from_millis(100));
-        if tries == 0 {
-            panic!("unable to healthcheck (start?)");
+    }
+    assert!(!old.exists());
+    assert!(recent.exists());
+    Ok(())
+}
+
+#[test]
+fn evict() -> Result<()> {
+    let data = tempfile::tempdir()?;
+    let old = data.path().join("2020-01-01T00:00:00Z.events.archiv");
+    let newer = data.path().join("2020-01-02T00:00:00Z.events.archiv");
+    fs::write(&old, b"")?;
+    fs::write(&newer, b"")?;
+
+    let data_dir = data.path().to_str().expect("utf-8 temp dir");
+    // more than any disk has, so everything but the live file goes
+    let _app = Batchy::start_with(&[
+        ("BATCHY_DATA_DIR", data_dir),
+        ("BATCHY_MIN_FREE_BYTES", &u64::MAX.to_string()),
+        ("BATCHY_EVICT", "1"),
+    ])?;
+    for _ in 0..20 {
+        if !newer.exists() {
+            break;
         }
-        tries -= 1;
+        std::thread::sleep(Duration::from_millis(100));
     }
-    ureq::post("http://localhost:3000/store").send_string("hello world")?;
-    ureq::post("http://localhost:3000/store").send_string("goodbye world")?;
+    assert!(!old.exists());
+    assert!(!newer.exists());
+    let health: Value = serde_json::from_str(
+        &ureq::get("http://localhost:3000/healthcheck")
+            .call()?
+            .into_string()?,
+    )?;
+    let live = health["live_file_name"].as_str().expect("a name");
+    assert!(data.path().join(live).exists());
+    Ok(())
+}
 
+#[test]
+fn resume() -> Result<()> {
+    let data = tempfile::tempdir()?;
+    let data_dir = data.path().to_str().expect("utf-8 temp dir");
+    let env = [
+        ("BATCHY_DATA_DIR", data_dir),
+        ("BATCHY_RESUME_WITHIN", "1h"),
+    ];
+    {
+        let mut app = Batchy::start_with(&env)?;
+        ureq::post("http://localhost:3000/store").send_string("one")?;
+        ureq::post("http://localhost:3000/store").send_string("two")?;
+        // no chance to finish the file
+        app.child.0.kill()?;
+        app.child.0.wait()?;
+    }
+
+    let mut app = Batchy::start_with(&env)?;
+    let health: Value = serde_json::from_str(
+        &ureq::get("http://localhost:3000/healthcheck")
+            .call()?
+            .into_string()?,
+    )?;
+    assert_eq!(health["live_items"], 2);
+    ureq::post("http://localhost:3000/store").send_string("three")?;
     nix::sys::signal::kill(
-        nix::unistd::Pid::from_raw(app.0.id().try_into()?),
+        nix::unistd::Pid::from_raw(app.child.0.id().try_into()?),
         nix::sys::signal::Signal::SIGTERM,
     )?;
+    assert!(app.child.0.wait()?.success());
 
-    assert!(app.0.wait()?.success());
-    let mut items = Vec::new();
+    let files = fs::read_dir(data.path())?.count();
+    assert_eq!(files, 1);
+    let items = read_events(data.path())?
+        .into_iter()
+        .map(|(_, body)| String::from_utf8(body))
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(items, ["one", "two", "three"]);
+    Ok(())
+}
 
-    for entry in fs::read_dir(home.path())? {
-        let entry = entry?;
-        if entry.file_type()?.is_file() {
-            let name = entry.file_name();
-            let name = name.to_str().unwrap();
-            if !name.ends_with(".events.archiv") {
-                continue;
+#[test]
+fn catalog_after_cycle() -> Result<()> {
+    let app = Batchy::start_with(&[("BATCHY_CATALOG", "1")])?;
+    // which can't be described, but mustn't stop the others being listed
+    fs::write(
+        app.home.path().join("2020-01-01T00:00:00Z.events.archiv"),
+        b"not an archiv",
+    )?;
+    ureq::post("http://localhost:3000/store").send_string("hello")?;
+    let health: Value = serde_json::from_str(
+        &ureq::get("http://localhost:3000/healthcheck")
+            .call()?
+            .into_string()?,
+    )?;
+    let previous = health["live_file_name"].as_str().expect("a name");
+    let previous = previous
+        .strip_suffix(".events.archiv")
+        .expect("an event file");
+    ureq::post("http://localhost:3000/api/cycle").call()?;
+
+    let listed = || -> Result<bool> {
+        let catalog = match fs::read_to_string(app.home.path().join("catalog.jsonl")) {
+            Ok(catalog) => catalog,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+        for line in catalog.lines() {
+            let entry: Value = serde_json::from_str(line)?;
+            if entry["name"] == previous {
+                assert_eq!(entry["items"], 1);
+                return Ok(true);
             }
-            let opts = archiv::ExpandOptions::default();
-            let mut archiv = opts.stream(io::BufReader::new(fs::File::open(entry.path())?))?;
-            while let Some(mut item) = archiv.next_item()? {
-                let mut s = Vec::new();
-                item.read_to_end(&mut s)?;
-                items.push(String::from_utf8(s[8..].to_vec())?);
+        }
+        Ok(false)
+    };
+    for _ in 0..20 {
+        if listed()? {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    panic!("{previous} not in the catalog");
+}
+
+#[test]
+fn checkpoint() -> Result<()> {
+    let app = Batchy::start()?;
+    ureq::post("http://localhost:3000/store").send_string("hello")?;
+    let checkpointed: Value = serde_json::from_str(
+        &ureq::post("http://localhost:3000/api/checkpoint")
+            .call()?
+            .into_string()?,
+    )?;
+    assert_eq!(checkpointed["durable"], true);
+    let name = checkpointed["name"].as_str().expect("a name");
+    let file_name = format!("{name}.events.archiv");
+
+    // an unfinished file would run out before the footer, which is an error
+    let path = app.home.path().join(&file_name);
+    let opts = archiv::ExpandOptions::default();
+    let mut archiv = opts.stream(io::BufReader::new(fs::File::open(path)?))?;
+    let mut items = Vec::new();
+    while let Some(mut item) = archiv.next_item()? {
+        let mut s = Vec::new();
+        item.read_to_end(&mut s)?;
+        items.push(s.split_off(8));
+    }
+    assert_eq!(items, [b"hello"]);
+
+    let health: Value = serde_json::from_str(
+        &ureq::get("http://localhost:3000/healthcheck")
+            .call()?
+            .into_string()?,
+    )?;
+    assert_ne!(health["live_file_name"], file_name.as_str());
+    Ok(())
+}
+
+#[test]
+fn pause() -> Result<()> {
+    let _app = Batchy::start()?;
+    ureq::post("http://localhost:3000/api/pause").call()?;
+
+    match ureq::post("http://localhost:3000/store").send_string("refused") {
+        Err(ureq::Error::Status(503, resp)) => {
+            assert!(resp.header("retry-after").is_some());
+            let body: Value = serde_json::from_str(&resp.into_string()?)?;
+            assert_eq!(body, json!({ "error": "ingestion paused" }));
+        }
+        other => panic!("expected a 503, got {other:?}"),
+    }
+    match ureq::get("http://localhost:3000/readyz").call() {
+        Err(ureq::Error::Status(503, _)) => (),
+        other => panic!("expected a 503, got {other:?}"),
+    }
+
+    let status: Value = serde_json::from_str(
+        &ureq::get("http://localhost:3000/api/status")
+            .call()?
+            .into_string()?,
+    )?;
+    assert_eq!(status["accepting"], false);
+    assert_eq!(status["paused"], true);
+    assert_eq!(status["breaker"], "closed");
+
+    ureq::post("http://localhost:3000/api/resume").call()?;
+    assert_eq!(
+        ureq::post("http://localhost:3000/store")
+            .send_string("accepted")?
+            .status(),
+        200
+    );
+    assert_eq!(
+        ureq::get("http://localhost:3000/readyz").call()?.status(),
+        200
+    );
+    Ok(())
+}
+
+#[test]
+fn data_dir() -> Result<()> {
+    let app = Batchy::start_with(&[("BATCHY_DATA_DIR", "data/events"), ("BATCHY_SEQUENCE", "1")])?;
+    ureq::post("http://localhost:3000/store").send_string("hello")?;
+    let dir = app.home.path().join("data/events");
+    fs::write(dir.join("notes.txt"), "not an event file")?;
+
+    let listed: Value = serde_json::from_str(
+        &ureq::get("http://localhost:3000/api/raw")
+            .call()?
+            .into_string()?,
+    )?;
+    let listed = listed.as_array().expect("a list");
+    assert_eq!(listed.len(), 1);
+    let name = listed[0]["name"].as_str().expect("a name");
+    assert!(dir.join(format!("{name}.events.archiv")).is_file());
+    Ok(())
+}
+
+#[test]
+fn listing_stats() -> Result<()> {
+    let _app = Batchy::start_with(&[("BATCHY_MAX_EVENTS", "2")])?;
+    for body in ["one", "three"] {
+        ureq::post("http://localhost:3000/store").send_string(body)?;
+    }
+    let listed: Value = serde_json::from_str(
+        &ureq::get("http://localhost:3000/api/raw?stats=true")
+            .call()?
+            .into_string()?,
+    )?;
+    let listed = listed.as_array().expect("a list");
+    assert_eq!(listed.len(), 2);
+    let (finished, live) = (&listed[0], &listed[1]);
+    assert_eq!(finished["item_count"], 2);
+    // timestamps, and the bodies
+    assert_eq!(finished["uncompressed_size"], 8 + 3 + 8 + 5);
+    assert_eq!(live["live"], true);
+    assert!(live.get("item_count").is_none());
+
+    let listed: Value = serde_json::from_str(
+        &ureq::get("http://localhost:3000/api/raw")
+            .call()?
+            .into_string()?,
+    )?;
+    assert!(listed[0].get("item_count").is_none());
+    Ok(())
+}
+
+#[test]
+fn max_body() -> Result<()> {
+    let _app = Batchy::start_with(&[("BATCHY_MAX_BODY", "10")])?;
+    let resp = ureq::post("http://localhost:3000/store").send_string("0123456789")?;
+    assert_eq!(resp.status(), 200);
+    match ureq::post("http://localhost:3000/store").send_string("0123456789a") {
+        Err(ureq::Error::Status(413, resp)) => {
+            let body: Value = serde_json::from_str(&resp.into_string()?)?;
+            assert_eq!(
+                body,
+                json!({"error": "too long", "limit_bytes": 10, "received_bytes": 11})
+            );
+        }
+        other => panic!("expected a 413, got {other:?}"),
+    }
+    Ok(())
+}
+
+#[test]
+fn undecodable_name() -> Result<()> {
+    let _app = Batchy::start()?;
+    for path in [
+        "/api/raw/%FF",
+        "/api/raw/%C3%28/header",
+        "/api/search/%FF?field=a&value=b",
+    ] {
+        match ureq::get(&format!("http://localhost:3000{path}")).call() {
+            Err(ureq::Error::Status(400, resp)) => {
+                let body: Value = serde_json::from_str(&resp.into_string()?)?;
+                assert_eq!(body, json!({"error": "invalid name"}), "{path}");
             }
+            other => panic!("expected a 400 for {path}, got {other:?}"),
         }
     }
+    Ok(())
+}
 
-    assert_eq!(items, vec!["hello world", "goodbye world"]);
+#[test]
+fn delete() -> Result<()> {
+    let app = Batchy::start()?;
+    let outside = app.home.path().join("outside.events.archiv");
+    fs::write(&outside, b"")?;
+    let old = "2020-01-01T00:00:00Z";
+    fs::write(app.home.path().join(format!("{old}.events.archiv")), b"")?;
+    let live = fs::read_dir(app.home.path())?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter_map(|name| Some(name.strip_suffix(".events.archiv")?.to_string()))
+        .find(|name| name != old && name != "outside")
+        .expect("a live file");
+
+    let status =
+        |name: &str| match ureq::delete(&format!("http://localhost:3000/api/raw/{name}")).call() {
+            Ok(resp) => resp.status(),
+            Err(ureq::Error::Status(status, _)) => status,
+            Err(err) => panic!("{err}"),
+        };
+    assert_eq!(status("..%2Foutside"), 400);
+    assert!(outside.exists());
+    assert_eq!(status(&live), 409);
+    assert_eq!(status(old), 200);
+    assert_eq!(status(old), 404);
+    Ok(())
+}
+
+#[test]
+fn delete_forgets_recent() -> Result<()> {
+    let _app = Batchy::start()?;
+    let live = || -> Result<String> {
+        let health: Value = serde_json::from_str(
+            &ureq::get("http://localhost:3000/healthcheck")
+                .call()?
+                .into_string()?,
+        )?;
+        let name = health["live_file_name"].as_str().expect("a name");
+        Ok(name
+            .strip_suffix(".events.archiv")
+            .expect("an event file")
+            .to_string())
+    };
+    ureq::post("http://localhost:3000/store").send_string("doomed")?;
+    let doomed = live()?;
+    ureq::post("http://localhost:3000/api/cycle").call()?;
+    ureq::post("http://localhost:3000/store").send_string("kept")?;
+    ureq::delete(&format!("http://localhost:3000/api/raw/{doomed}")).call()?;
 
+    let tail = ureq::get("http://localhost:3000/api/tail?n=2")
+        .call()?
+        .into_string()?;
+    let bodies = tail
+        .lines()
+        .map(|line| Ok(serde_json::from_str::<Value>(line)?["body"].clone()))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(bodies, [json!("kept")]);
+    Ok(())
+}
+
+#[test]
+fn recompress() -> Result<()> {
+    let app = Batchy::start()?;
+    ureq::post("http://localhost:3000/store").send_string("hello")?;
+    let checkpointed: Value = serde_json::from_str(
+        &ureq::post("http://localhost:3000/api/checkpoint")
+            .call()?
+            .into_string()?,
+    )?;
+    let name = checkpointed["name"].as_str().expect("a name");
+    let url = format!("http://localhost:3000/api/raw/{name}/recompress?level=19");
+    let status = || match ureq::post(&url).call() {
+        Ok(resp) => resp.status(),
+        Err(ureq::Error::Status(status, _)) => status,
+        Err(err) => panic!("{err}"),
+    };
+
+    // as if someone else was half way through
+    let temp = app
+        .home
+        .path()
+        .join(format!("{name}.events.archiv.recompress.tmp"));
+    fs::write(&temp, b"theirs")?;
+    assert_eq!(status(), 409);
+    assert_eq!(fs::read(&temp)?, b"theirs");
+    fs::remove_file(&temp)?;
+
+    assert_eq!(status(), 200);
+    let events: Value = serde_json::from_str(
+        &ureq::get(&format!("http://localhost:3000/api/events/{name}"))
+            .call()?
+            .into_string()?,
+    )?;
+    assert_eq!(events[0]["body"], "hello");
     Ok(())
 }
 
+#[test]
+fn import() -> Result<()> {
+    let app = Batchy::start()?;
+    ureq::post("http://localhost:3000/store").send_string("hello")?;
+    let checkpointed: Value = serde_json::from_str(
+        &ureq::post("http://localhost:3000/api/checkpoint")
+            .call()?
+            .into_string()?,
+    )?;
+    let existing = checkpointed["name"].as_str().expect("a name");
+    let mut archiv = Vec::new();
+    ureq::get(&format!("http://localhost:3000/api/raw/{existing}"))
+        .call()?
+        .into_reader()
+        .read_to_end(&mut archiv)?;
+
+    let put =
+        |name: &str, body: &[u8]| match ureq::put(&format!("http://localhost:3000/api/raw/{name}"))
+            .send_bytes(body)
+        {
+            Ok(resp) => Ok((resp.status(), resp.into_string()?)),
+            Err(ureq::Error::Status(status, resp)) => Ok((status, resp.into_string()?)),
+            Err(err) => Err(anyhow::Error::from(err)),
+        };
+    let restored = "2020-01-01T00:00:00Z";
+    let (status, body) = put(restored, &archiv)?;
+    assert_eq!(status, 200);
+    let body: Value = serde_json::from_str(&body)?;
+    assert_eq!(body["items"], 1);
+    let events: Value = serde_json::from_str(
+        &ureq::get(&format!("http://localhost:3000/api/events/{restored}"))
+            .call()?
+            .into_string()?,
+    )?;
+    assert_eq!(events[0]["body"], "hello");
+
+    assert_eq!(put("2020-01-02T00:00:00Z", b"not an archiv")?.0, 400);
+    assert!(!app
+        .home
+        .path()
+        .join("2020-01-02T00:00:00Z.events.archiv")
+        .exists());
+    assert_eq!(put(restored, &archiv)?.0, 409);
+    Ok(())
+}
+
+#[test]
+fn empty_files() -> Result<()> {
+    let app = Batchy::start()?;
+    let empty = "2020-01-01T00:00:00Z";
+    // as left by a crash just after creating a file, and just after starting the header
+    let partial = "2020-01-02T00:00:00Z";
+    fs::write(app.home.path().join(format!("{empty}.events.archiv")), b"")?;
+    fs::write(
+        app.home.path().join(format!("{partial}.events.archiv")),
+        [0x28, 0xb5, 0x2f, 0xfd],
+    )?;
+
+    for name in [empty, partial] {
+        for path in [
+            format!("/api/raw/{name}"),
+            format!("/api/raw/{name}/ratio"),
+            format!("/api/search/{name}?field=a&value=b"),
+            format!("/api/events/{name}.gz"),
+            format!("/api/events/{name}"),
+            format!("/api/raw/{name}/items"),
+            "/api/events".to_string(),
+            "/api/range".to_string(),
+            "/api/aggregate".to_string(),
+            "/api/recent?files=3".to_string(),
+        ] {
+            let resp = ureq::get(&format!("http://localhost:3000{path}")).call()?;
+            assert_eq!(resp.status(), 200, "{path}");
+        }
+    }
+
+    let range: Value = serde_json::from_str(
+        &ureq::get("http://localhost:3000/api/range")
+            .call()?
+            .into_string()?,
+    )?;
+    assert_eq!(range, json!({"oldest": null, "newest": null}));
+    let recent = ureq::get("http://localhost:3000/api/recent?files=3")
+        .call()?
+        .into_string()?;
+    assert_eq!(recent, "");
+    Ok(())
+}
+
+// every instance listens on the same port, so only one test can have one at a time
+static PORT: Mutex<()> = Mutex::new(());
+
+struct Batchy {
+    // dropped in order: kill the app before giving up the port, or the directory
+    child: KillOnDrop,
+    home: tempfile::TempDir,
+    _port: MutexGuard<'static, ()>,
+}
+
+impl Batchy {
+    fn start() -> Result<Batchy> {
+        Batchy::start_with(&[])
+    }
+
+    fn start_with(env: &[(&str, &str)]) -> Result<Batchy> {
+        let port = PORT.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let home = tempfile::tempdir()?;
+        let child = KillOnDrop(
+            Command::new(env!("CARGO_BIN_EXE_batchy"))
+                .current_dir(home.path())
+                .envs(env.iter().copied())
+                .spawn()?,
+        );
+        let mut tries = 10;
+        loop {
+            if let Ok(resp) = ureq::get("http://localhost:3000/healthcheck").call() {
+                assert_eq!(resp.status(), 200);
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+            if tries == 0 {
+                panic!("unable to healthcheck (start?)");
+            }
+            tries -= 1;
+        }
+        Ok(Batchy {
+            child,
+            home,
+            _port: port,
+        })
+    }
+}
+
 struct KillOnDrop(std::process::Child);
 
 impl Drop for KillOnDrop {
     fn drop(&mut self) {
         let _ = self.0.kill();
+        let _ = self.0.wait();
     }
 }